@@ -16,6 +16,9 @@ with import <nixpkgs> {};
       # aubio
       clang
 
+      # radio
+      ffmpeg
+
       # macroquad
       # TODO
     ];
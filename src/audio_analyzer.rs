@@ -1,235 +1,902 @@
 // SPDX-License-Identifier: EUPL-1.2
 
 use std::{
-    ffi::CString,
-    io::{BufReader, Read},
-    os::unix::net::UnixStream,
+    collections::VecDeque,
+    str::FromStr,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+    thread,
+    time::{Duration, Instant},
 };
 
-use anyhow::{bail, Context};
+use anyhow::bail;
 use lockfree::channel::spsc;
-use pulseaudio::protocol;
 use simple_moving_average::{SumTreeSMA, SMA};
 use soundtouch::BPMDetect;
-// TODO Enable FFT analysis when running in "real-time" (double check performance usage)
-// use spectrum_analyzer::scaling::scale_to_zero_to_one;
-// use spectrum_analyzer::{samples_fft_to_spectrum, FrequencyLimit};
+
+use crate::audio_source::{
+    self,
+    pcm::PcmSpec,
+    pulse::{Capture, Mix},
+    shift, AudioSource, Input, Notice,
+};
+
+pub mod beat;
+pub mod groove;
+pub mod key;
+pub mod loudness;
+pub mod section;
+pub mod silence;
+pub mod spectrum;
+pub mod timbre;
+
+/// An event with the monotonic time at which the samples it comes from are heard, or were
+/// captured for a source that is not played.
+#[derive(Debug)]
+pub struct Timed {
+    pub time: Instant,
+    pub event: Event,
+}
 
 #[derive(Debug)]
 pub enum Event {
-    Tempo { average: f32, accuracy: f32 },
-    Volume { average: f32 },
+    Tempo {
+        average: f32,
+        accuracy: f32,
+    },
+    /// Momentary loudness of the source in 0..1, relative to its recent loudness range. Measured
+    /// over all channels whatever the downmix, as BS.1770 sums them.
+    Volume {
+        average: f32,
+    },
+    /// Momentary and short-term loudness of the source, in LUFS.
+    Loudness {
+        momentary: f32,
+        short_term: f32,
+    },
+    /// Level of a single channel of the source, scaled like `Volume`.
+    ChannelVolume {
+        channel: u8,
+        average: f32,
+    },
+    /// Stereo image of the first two channels of the source. `balance` goes from -1.0 (left) to
+    /// 1.0 (right) and `width` from 0.0 (mono) to 1.0 (out of phase).
+    Stereo {
+        balance: f32,
+        width: f32,
+    },
+    /// Smoothed levels of the `spectrum::BANDS` frequency bands, from sub-bass to brilliance,
+    /// normalized to 0..1 against their recent peak.
+    Spectrum {
+        bands: [f32; spectrum::BANDS],
+    },
+    /// A beat at `time` seconds of audio. `strength` is the relative strength of the onset found
+    /// on the beat, 0.0 when the beat was extrapolated from the tempo, and `phase` the distance of
+    /// that onset from the predicted beat, in beats.
+    Beat {
+        time: f64,
+        strength: f32,
+        phase: f32,
+    },
+    /// A downbeat, starting the bar number `index` of `beats_per_bar` beats.
+    Bar {
+        index: u64,
+        beats_per_bar: u8,
+    },
+    /// Drum pattern of the last bars. `skank` goes from 0.0 to 1.0 as the guitar and keys move
+    /// to the offbeats.
+    Groove {
+        style: groove::Style,
+        confidence: f32,
+        skank: f32,
+    },
+    /// Smoothed timbre descriptors, see `timbre::Timbre`.
+    Timbre {
+        centroid: f32,
+        flatness: f32,
+        rolloff: f32,
+        flux: f32,
+    },
+    /// Energy of the pitch classes from C to B, relative to the strongest one.
+    Chroma {
+        bins: [f32; key::PITCH_CLASSES],
+    },
+    /// Key of the last seconds. `tonic` is the pitch class of the tonic, 0 for C.
+    Key {
+        tonic: u8,
+        mode: key::Mode,
+        confidence: f32,
+    },
+    /// The music entered a new section.
+    Section {
+        kind: section::Kind,
+        confidence: f32,
+    },
+    /// The source went quiet for a while (`true`), keeping the tempo, or played again (`false`).
+    Breakdown {
+        active: bool,
+    },
+    /// Another of the mixed sources became the loudest one.
+    Dominant {
+        source: String,
+    },
+    /// A new track started, as announced by the source.
+    Track {
+        title: String,
+    },
+    /// Something else happened to the source, for diagnostics.
+    Diagnostic(Notice),
     Reset,
+    /// The source failed, no events come until it is restored.
+    SourceLost,
+    SourceRestored,
 }
 
-const SILENCE_RMS: f32 = 0.01;
-const SILENCE_TIME: f32 = 0.618; // Number of seconds of silence to consider reset
-
-pub fn run(event_tx: &mut spsc::Sender<Event>) -> anyhow::Result<()> {
-    let (mut sock, protocol_version) = connect_and_init().context("failed to initialize client")?;
-
-    protocol::write_command_message(
-        sock.get_mut(),
-        10,
-        protocol::Command::GetSourceInfo(protocol::GetSourceInfo {
-            // TODO Make this configurable
-            // name: Some(CString::new("@DEFAULT_SOURCE@")?),
-            name: Some(CString::new("alsa_output.usb-Focusrite_Scarlett_4i4_USB_D8JXNH21A2049A-00.analog-surround-40.monitor")?),
-            ..Default::default()
-        }),
-        protocol_version,
-    )?;
-
-    let (_, source_info) =
-        protocol::read_reply_message::<protocol::SourceInfo>(&mut sock, protocol_version)?;
-    eprintln!(
-        "recording from source: {:?}...",
-        source_info.description.unwrap_or(source_info.name)
-    );
-
-    // TODO Support stereo input? or optionally merge stereo signals
-    let channels = 1; // source_info.channel_map.num_channels()
-
-    // Create the recording stream on the server.
-    protocol::write_command_message(
-        sock.get_mut(),
-        99,
-        protocol::Command::CreateRecordStream(protocol::RecordStreamParams {
-            source_index: Some(source_info.index),
-            sample_spec: protocol::SampleSpec {
-                format: source_info.sample_spec.format,
-                channels: channels,
-                sample_rate: source_info.sample_spec.sample_rate,
-            },
-            channel_map: source_info.channel_map,
-            cvolume: Some(protocol::ChannelVolume::norm(2)),
-            ..Default::default()
-        }),
-        protocol_version,
-    )?;
-
-    let (_, record_stream) = protocol::read_reply_message::<protocol::CreateRecordStreamReply>(
-        &mut sock,
-        protocol_version,
-    )?;
-
-    // Create the output file.
-    // TODO Support different sample formats
-    let (_bits_per_sample, _sample_format) = match record_stream.sample_spec.format {
-        protocol::SampleFormat::S16Le => (16, hound::SampleFormat::Int),
-        protocol::SampleFormat::Float32Le => (32, hound::SampleFormat::Float),
-        protocol::SampleFormat::S32Le => (32, hound::SampleFormat::Int),
-        _ => bail!(
-            "unsupported sample format: {:?}",
-            record_stream.sample_spec.format
-        ),
-    };
+/// Analyzer settings.
+#[derive(Debug, Clone, Default)]
+pub struct Config {
+    pub input: Input,
+    /// The PulseAudio sources to record, the monitor of the default sink if empty.
+    pub sources: Vec<Capture>,
+    pub mix: Mix,
+    pub pcm: PcmSpec,
+    pub downmix: Downmix,
+    pub agc: loudness::AgcConfig,
+    pub silence: silence::SilenceConfig,
+    pub timing: Timing,
+    /// Analyze inputs that are not live as fast as possible instead of in real time.
+    pub fast: bool,
+}
 
-    let frame_size = 16384;
-    let frame_time = frame_size as f32 / record_stream.sample_spec.sample_rate as f32;
-    eprintln!("frame_time: {:#?}", frame_time);
+/// Analysis window and latency settings.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Timing {
+    /// Samples per channel between two tempo updates, rounded up to a multiple of `BLOCK_SIZE`.
+    /// Longer windows give steadier tempos, shorter ones react faster.
+    pub window: usize,
+    /// Seconds of audio in a packet from the PulseAudio server.
+    pub fragment: f32,
+    /// Also send the volume every `LOW_LATENCY_SIZE` samples per channel, not once per window.
+    pub low_latency: bool,
+    /// Milliseconds by which the display delays its reactions past the time of the events, for
+    /// speakers behind a DSP or a Bluetooth link. Negative to react earlier.
+    pub offset: i64,
+}
 
-    eprintln!("stream: {:#?}", record_stream);
+impl Default for Timing {
+    fn default() -> Self {
+        Timing {
+            window: 16384,
+            fragment: 0.02,
+            low_latency: false,
+            offset: 0,
+        }
+    }
+}
 
-    // A reusable buffer.
-    let mut buf = vec![0; record_stream.buffer_attr.fragment_size as usize];
+/// How the channels of the source are merged into the signal used for tempo, beats, spectrum and
+/// silence detection. Volume and loudness are always measured over all channels.
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub enum Downmix {
+    /// Average of all channels.
+    #[default]
+    Mid,
+    /// First channel only.
+    Left,
+    /// Second channel only (or the first one of a mono source).
+    Right,
+    /// Loudest channel for every sample.
+    Max,
+    /// Like `Mid`, but silence is detected from the loudest channel level, so that out of phase
+    /// content does not cancel out into silence.
+    MidMaxSilence,
+}
 
-    let mut bpm_detect = BPMDetect::new(1, record_stream.sample_spec.sample_rate);
+impl FromStr for Downmix {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> anyhow::Result<Self> {
+        match s {
+            "mid" => Ok(Downmix::Mid),
+            "left" => Ok(Downmix::Left),
+            "right" => Ok(Downmix::Right),
+            "max" => Ok(Downmix::Max),
+            "mid-max-silence" => Ok(Downmix::MidMaxSilence),
+            _ => bail!(
+                "unknown downmix {:?} (mid, left, right, max or mid-max-silence)",
+                s
+            ),
+        }
+    }
+}
 
-    let mut bpm_sma = SumTreeSMA::<_, f32, 128>::new();
-    let mut rms_sma = SumTreeSMA::<_, f32, 512>::new();
-    let mut silence: usize = 0;
-    let silence_reset: usize =
-        (SILENCE_TIME * record_stream.sample_spec.sample_rate as f32).floor() as usize;
+impl Downmix {
+    /// Merges the interleaved samples of `frame` into `out`.
+    fn mix(&self, frame: &[f32], channels: usize, out: &mut Vec<f32>) {
+        let samples = frame.chunks_exact(channels);
+        match self {
+            Downmix::Mid | Downmix::MidMaxSilence => {
+                out.extend(samples.map(|s| s.iter().sum::<f32>() / channels as f32))
+            }
+            Downmix::Left => out.extend(samples.map(|s| s[0])),
+            Downmix::Right => out.extend(samples.map(|s| s[channels.min(2) - 1])),
+            Downmix::Max => out.extend(samples.map(|s| {
+                s.iter()
+                    .copied()
+                    .fold(0.0, |a: f32, b| if b.abs() > a.abs() { b } else { a })
+            })),
+        }
+    }
+}
+
+const BACKOFF_MIN: Duration = Duration::from_millis(500);
+const BACKOFF_MAX: Duration = Duration::from_secs(16);
+const STOP_CHECK: Duration = Duration::from_millis(100); // Between two checks while waiting
+
+/// Asks `supervise` and `run` to return, from another thread. It is checked between two reads of
+/// the source, which time out on sockets and pipes, and while a source waits on its server.
+#[derive(Debug, Clone, Default)]
+pub struct Stop(Arc<AtomicBool>);
+
+impl Stop {
+    pub fn stop(&self) {
+        self.0.store(true, Ordering::Relaxed);
+    }
+
+    pub fn is_stopped(&self) -> bool {
+        self.0.load(Ordering::Relaxed)
+    }
 
-    // Read messages from the server in a loop. In real code it would be more
-    // efficient to poll the socket using `mio` or similar.
+    /// Sleeps for `duration`, or less if stopped meanwhile.
+    fn sleep(&self, duration: Duration) {
+        let start = Instant::now();
+        while !self.is_stopped() {
+            let Some(left) = duration.checked_sub(start.elapsed()) else {
+                break;
+            };
+            thread::sleep(left.min(STOP_CHECK));
+        }
+    }
+}
+
+/// Analyzes `source` like `run`, reopening the input of `config` with an exponential backoff when
+/// it fails, if the input reconnects. Without a `source`, as when the server is not up yet, the
+/// input is opened with the same backoff.
+pub fn supervise(
+    event_tx: &mut spsc::Sender<Timed>,
+    mut source: Option<Box<dyn AudioSource>>,
+    config: &Config,
+    stop: &Stop,
+) -> anyhow::Result<()> {
+    let mut backoff = BACKOFF_MIN;
     loop {
-        let desc = protocol::read_descriptor(&mut sock)?;
-
-        // A channel of -1 is a command message. Everything else is data.
-        if desc.channel == u32::MAX {
-            let (_, msg) = protocol::Command::read_tag_prefixed(&mut sock, protocol_version)?;
-            eprintln!("received command from server: {:#?}", msg);
-        } else {
-            buf.resize(desc.length as usize, 0);
-            sock.read_exact(&mut buf)?;
-
-            let frame: Vec<f32> = buf
-                .chunks_exact(4)
-                .map(TryInto::try_into)
-                .map(Result::unwrap)
-                .map(i32::from_le_bytes)
-                .map(|x| x as f32 / i32::MAX as f32)
-                .collect();
-
-            debug_assert!(frame_size == frame.len());
-
-            if silence > silence_reset {
-                rms_sma = SumTreeSMA::<_, f32, 512>::new();
+        let start = Instant::now();
+        if let Some(mut source) = source.take() {
+            let err = match run(event_tx, source.as_mut(), config, stop) {
+                _ if stop.is_stopped() => return Ok(()),
+                Ok(()) if !config.input.reconnects() => return Ok(()),
+                Ok(()) => anyhow::anyhow!("end of input"),
+                Err(err) if !config.input.reconnects() => return Err(err),
+                Err(err) => err,
+            };
+            eprintln!("source lost: {:#}", err);
+        }
+        send(event_tx, Instant::now(), Event::SourceLost);
+
+        if start.elapsed() > BACKOFF_MAX {
+            backoff = BACKOFF_MIN;
+        }
+        source = Some(loop {
+            stop.sleep(backoff);
+            if stop.is_stopped() {
+                return Ok(());
+            }
+            backoff = (backoff * 2).min(BACKOFF_MAX);
+            match audio_source::open(config, stop) {
+                Ok(source) => break source,
+                Err(err) => eprintln!("failed to reopen source: {:#}", err),
             }
+        });
+        eprintln!("source restored");
+        send(event_tx, Instant::now(), Event::SourceRestored);
+    }
+}
 
-            for block in frame.chunks_exact(frame_size / 256) {
-                bpm_detect.input_samples(&block);
-                let rms =
-                    (block.iter().map(|x| x.powf(2.0)).sum::<f32>() / block.len() as f32).sqrt();
-                rms_sma.add_sample(rms);
+// Events sent after the display stopped are dropped.
+fn send(event_tx: &mut spsc::Sender<Timed>, time: Instant, event: Event) {
+    let _ = event_tx.send(Timed { time, event });
+}
+
+/// Analyzes `source` until its end. Sources that are not live are paced in real time unless
+/// `config.fast` is set. Each event is timed from the sample it happens at, counted back from the
+/// time of the last sample read: as told by the source if it knows its latency, by the pace of
+/// the samples otherwise. Returns early once `stop` is stopped.
+pub fn run(
+    event_tx: &mut spsc::Sender<Timed>,
+    source: &mut dyn AudioSource,
+    config: &Config,
+    stop: &Stop,
+) -> anyhow::Result<()> {
+    let sample_rate = source.sample_rate();
+    let channels = source.channels();
+    let mut analyzer = Analyzer::new(sample_rate, channels, config);
+    eprintln!("frame_time: {:#?}", analyzer.frame_time());
+
+    let paced = !config.fast && !source.is_live();
+    let mut samples = Vec::new();
+    let mut frames = 0;
+    let mut suspended: Option<Instant> = None;
+    let start = Instant::now();
+    while !stop.is_stopped() {
+        samples.clear();
+        if !source.read(&mut samples)? {
+            eprintln!("end of input");
+            return Ok(());
+        }
+        // A suspended source sends nothing, and the time it stays so is analyzed as silence: a
+        // short pause goes through a breakdown before the reset, like a quiet passage.
+        let filled = suspended.is_some() && samples.is_empty();
+        if let (Some(since), true) = (suspended.as_mut(), filled) {
+            let silent = (since.elapsed().as_secs_f64() * sample_rate as f64) as usize;
+            *since += Duration::from_secs_f64(silent as f64 / sample_rate as f64);
+            samples.resize(silent * channels, 0.0);
+        }
+        frames += samples.len() / channels;
+        let due = Duration::from_secs_f64(frames as f64 / sample_rate as f64);
+        let time = match source.time() {
+            Some(time) if !filled => time,
+            None if paced => start + due,
+            _ => Instant::now(),
+        };
+
+        while let Some(notice) = source.notice() {
+            let event = match notice {
+                Notice::Track(title) => Event::Track { title },
+                Notice::Dominant { source } => Event::Dominant { source },
+                Notice::Suspended(active) => {
+                    suspended = active.then(Instant::now);
+                    Event::Diagnostic(notice)
+                }
+                notice => Event::Diagnostic(notice),
+            };
+            send(event_tx, time, event);
+        }
+        for (at, event) in analyzer.process(&samples) {
+            send(event_tx, shift(time, at - due.as_secs_f64()), event);
+        }
+
+        if paced {
+            if let Some(wait) = due.checked_sub(start.elapsed()) {
+                stop.sleep(wait);
             }
+        }
+    }
+    Ok(())
+}
 
-            let rms = rms_sma.get_average();
-
-            if rms > SILENCE_RMS {
-                silence = 0;
-
-                event_tx
-                    .send(Event::Volume {
-                        average: if rms < 0.2 { rms / 0.2 } else { 1.0 },
-                    })
-                    .expect("Can not send audio event");
-
-                let bpm_frame = bpm_detect.get_bpm();
-                if bpm_frame != 0.0 {
-                    let bpm_select = if bpm_sma.get_num_samples() > 0 {
-                        let average: f32 = bpm_sma.get_average();
-                        let mut select = bpm_frame;
-                        let mut select_delta = 300.0;
-                        for b in vec![bpm_frame, bpm_frame * 2.0, bpm_frame / 2.] {
-                            let delta = (b - average).abs();
-                            if delta < select_delta {
-                                select_delta = delta;
-                                select = b;
-                            }
-                        }
-                        select
-                    } else {
-                        bpm_frame
-                    };
+pub const BLOCK_SIZE: usize = 64; // Samples per channel analyzed at once
+pub const LOW_LATENCY_SIZE: usize = 1024; // Samples per channel between two low latency updates
+const TEMPO_TIME: f32 = 48.0; // Seconds over which the tempo is averaged
+const ACCURACY_TIME: f32 = 1.5; // Seconds of tempo for a full accuracy
+
+/// Turns interleaved samples into audio events, independently of where the samples come from.
+pub struct Analyzer {
+    sample_rate: u32,
+    channels: usize,
+    downmix: Downmix,
+    window: usize,
+    low_latency: bool,
+
+    block: Vec<f32>,
+    frame: Vec<f32>,
+    mono: Vec<f32>,
+    events: Vec<(f64, Event)>,
+    blocks: usize,
+
+    bpm_detect: BPMDetect,
+    spectrum: spectrum::Spectrum,
+    onsets: beat::OnsetDetector,
+    beats: beat::BeatTracker,
+    bars: beat::BarTracker,
+    grooves: groove::GrooveClassifier,
+    keys: key::KeyDetector,
+    timbre: timbre::TimbreAnalyzer,
+    sections: section::SectionDetector,
+    loudness: loudness::Loudness,
+    agc_config: loudness::AgcConfig,
+    agc: loudness::Agc,
+
+    tempos: VecDeque<f32>,
+    tempo_len: usize,
+    rms_sma: SumTreeSMA<f32, f32, 512>,
+    silence: silence::SilenceDetector,
+}
 
-                    bpm_sma.add_sample(bpm_select);
+impl Analyzer {
+    pub fn new(sample_rate: u32, channels: usize, config: &Config) -> Analyzer {
+        let window = config.timing.window.div_ceil(BLOCK_SIZE).max(1) * BLOCK_SIZE;
+        let frame_time = window as f32 / sample_rate as f32;
+        Analyzer {
+            sample_rate,
+            channels,
+            downmix: config.downmix,
+            window,
+            low_latency: config.timing.low_latency,
+            block: Vec::with_capacity(BLOCK_SIZE * channels),
+            frame: Vec::with_capacity(window * channels),
+            mono: Vec::with_capacity(BLOCK_SIZE),
+            events: Vec::new(),
+            blocks: 0,
+            bpm_detect: BPMDetect::new(1, sample_rate),
+            spectrum: spectrum::Spectrum::new(sample_rate),
+            onsets: beat::OnsetDetector::default(),
+            beats: beat::BeatTracker::default(),
+            bars: beat::BarTracker::default(),
+            grooves: groove::GrooveClassifier::default(),
+            keys: key::KeyDetector::default(),
+            timbre: timbre::TimbreAnalyzer::default(),
+            sections: section::SectionDetector::new(frame_time),
+            loudness: loudness::Loudness::new(sample_rate, channels),
+            agc_config: config.agc,
+            agc: loudness::Agc::new(config.agc),
+            tempos: VecDeque::new(),
+            tempo_len: ((TEMPO_TIME / frame_time).round() as usize).max(1),
+            rms_sma: SumTreeSMA::new(),
+            silence: silence::SilenceDetector::new(config.silence, sample_rate),
+        }
+    }
 
-                    let average = bpm_sma.get_average();
-                    let mut accuracy = bpm_sma.get_num_samples() as f32 / 4.0;
-                    if accuracy > 1.0 {
-                        accuracy = 1.0;
+    pub fn sample_rate(&self) -> u32 {
+        self.sample_rate
+    }
+
+    pub fn channels(&self) -> usize {
+        self.channels
+    }
+
+    /// Feeds interleaved `samples` of any length and returns the events of the blocks and frames
+    /// completed by them, each one with the time it happens at in seconds of audio, counted from
+    /// the first sample. Beats come within a block, the tempo once per window.
+    pub fn process(&mut self, samples: &[f32]) -> impl Iterator<Item = (f64, Event)> + '_ {
+        let block_len = BLOCK_SIZE * self.channels;
+        let mut samples = samples;
+        while !samples.is_empty() {
+            let take = (block_len - self.block.len()).min(samples.len());
+            self.block.extend_from_slice(&samples[..take]);
+            samples = &samples[take..];
+            if self.block.len() == block_len {
+                self.process_block();
+                self.frame.extend_from_slice(&self.block);
+                self.block.clear();
+                if self.frame.len() == self.window * self.channels {
+                    self.process_frame();
+                    self.frame.clear();
+                }
+            }
+        }
+        self.events.drain(..)
+    }
+
+    /// Duration of a frame, in seconds of audio.
+    fn frame_time(&self) -> f32 {
+        self.window as f32 / self.sample_rate as f32
+    }
+
+    /// Time of the end of the blocks processed so far, in seconds of audio.
+    fn time(&self) -> f64 {
+        (self.blocks * BLOCK_SIZE) as f64 / self.sample_rate as f64
+    }
+
+    fn process_block(&mut self) {
+        let channels = self.channels;
+        let end = ((self.blocks + 1) * BLOCK_SIZE) as f64 / self.sample_rate as f64;
+        let samples = &self.block;
+        let events = &mut self.events;
+
+        self.mono.clear();
+        self.downmix.mix(samples, channels, &mut self.mono);
+        let block = &self.mono;
+
+        self.bpm_detect.input_samples(block);
+        self.loudness.input_samples(samples);
+        let (onsets, beats, bars, grooves, keys, timbre) = (
+            &mut self.onsets,
+            &mut self.beats,
+            &mut self.bars,
+            &mut self.grooves,
+            &mut self.keys,
+            &mut self.timbre,
+        );
+        self.spectrum.input_samples(block, |spectrum| {
+            let onset = onsets.process(spectrum);
+            grooves.process(spectrum);
+            keys.process(spectrum);
+            timbre.process(spectrum);
+            if let Some(beat) = beats.process(onset, spectrum.time()) {
+                // Beats, and the bars and grooves they complete, come at the time of the beat.
+                events.push((
+                    beat.time,
+                    Event::Beat {
+                        time: beat.time,
+                        strength: beat.strength,
+                        phase: beat.phase,
+                    },
+                ));
+
+                let [sub_bass, bass, low_mid, ..] = spectrum.bands();
+                let accent = beat.strength * (sub_bass + bass + low_mid) / 3.0;
+                let bar = bars.process(accent);
+                if let (Some(period), Some((position, beats_per_bar))) =
+                    (beats.period(), bars.position())
+                {
+                    grooves.beat(beat.time, period, position, beats_per_bar);
+                }
+                if let Some(bar) = bar {
+                    events.push((
+                        beat.time,
+                        Event::Bar {
+                            index: bar.index,
+                            beats_per_bar: bar.beats_per_bar,
+                        },
+                    ));
+                    if let Some(groove) = grooves.bar() {
+                        events.push((
+                            beat.time,
+                            Event::Groove {
+                                style: groove.style,
+                                confidence: groove.confidence,
+                                skank: groove.skank,
+                            },
+                        ));
                     }
-                    event_tx
-                        .send(Event::Tempo { average, accuracy })
-                        .expect("Can not send audio event");
                 }
-            } else {
-                if silence < usize::MAX - frame_size && silence < silence_reset {
-                    silence += frame_size;
-                    if silence >= silence_reset {
-                        bpm_detect = BPMDetect::new(1, record_stream.sample_spec.sample_rate);
-                        bpm_sma = SumTreeSMA::<_, f32, 128>::new();
-                        event_tx
-                            .send(Event::Reset)
-                            .expect("Can not send audio event");
+            }
+        });
+        // Only drives silence detection, the volume comes from the loudness.
+        let rms = match self.downmix {
+            Downmix::MidMaxSilence => (0..channels)
+                .map(|channel| rms_of(samples.iter().skip(channel).step_by(channels)))
+                .fold(0.0, f32::max),
+            _ => rms_of(block.iter()),
+        };
+        self.rms_sma.add_sample(rms);
+
+        self.blocks += 1;
+        if self.low_latency
+            && self.blocks.is_multiple_of(LOW_LATENCY_SIZE / BLOCK_SIZE)
+            && self.silence.state() != silence::State::Silence
+        {
+            events.push((
+                end,
+                Event::Volume {
+                    average: self.agc.intensity(self.loudness.fast()),
+                },
+            ));
+        }
+    }
+
+    fn process_frame(&mut self) {
+        let channels = self.channels;
+        let frame = &self.frame;
+        let time = self.time();
+        let frame_time = self.frame_time();
+        let events = &mut self.events;
+        let mut push = |event| events.push((time, event));
+
+        let rms = self.rms_sma.get_average();
+
+        let transition = self.silence.process(rms, self.window);
+        match transition {
+            Some(silence::Transition::Breakdown) => push(Event::Breakdown { active: true }),
+            Some(silence::Transition::Resume) => push(Event::Breakdown { active: false }),
+            _ => {}
+        }
+
+        let state = self.silence.state();
+        if state != silence::State::Silence {
+            let momentary = self.loudness.momentary();
+            let volume = self.agc.process(momentary, frame_time);
+            push(Event::Volume { average: volume });
+            push(Event::Loudness {
+                momentary,
+                short_term: self.loudness.short_term(),
+            });
+
+            for channel in 0..channels {
+                let lufs = self.loudness.channel_momentary(channel);
+                push(Event::ChannelVolume {
+                    channel: channel as u8,
+                    average: self.agc.intensity(lufs),
+                });
+            }
+            if channels >= 2 {
+                let (balance, width) = stereo(frame, channels);
+                push(Event::Stereo { balance, width });
+            }
+
+            push(Event::Spectrum {
+                bands: self.spectrum.bands(),
+            });
+            let timbre = self.timbre.timbre();
+            push(Event::Timbre {
+                centroid: timbre.centroid,
+                flatness: timbre.flatness,
+                rolloff: timbre.rolloff,
+                flux: timbre.flux,
+            });
+            push(Event::Chroma {
+                bins: self.keys.chroma(),
+            });
+            if let Some(key) = self.keys.key() {
+                push(Event::Key {
+                    tonic: key.tonic,
+                    mode: key.mode,
+                    confidence: key.confidence,
+                });
+            }
+
+            let features = section::Features {
+                bands: self.spectrum.bands(),
+                chroma: self.keys.chroma(),
+                timbre,
+                volume,
+            };
+            if let Some(section) = self.sections.process(&features) {
+                push(Event::Section {
+                    kind: section.kind,
+                    confidence: section.confidence,
+                });
+            }
+        }
+
+        // The tempo of a breakdown is kept as it was before.
+        if state == silence::State::Sound {
+            let bpm_frame = self.bpm_detect.get_bpm();
+            if bpm_frame != 0.0 {
+                let bpm_select = if !self.tempos.is_empty() {
+                    let average = self.tempo();
+                    let mut select = bpm_frame;
+                    let mut select_delta = 300.0;
+                    for b in vec![bpm_frame, bpm_frame * 2.0, bpm_frame / 2.] {
+                        let delta = (b - average).abs();
+                        if delta < select_delta {
+                            select_delta = delta;
+                            select = b;
+                        }
                     }
+                    select
+                } else {
+                    bpm_frame
+                };
+
+                self.tempos.push_back(bpm_select);
+                if self.tempos.len() > self.tempo_len {
+                    self.tempos.pop_front();
                 }
+
+                let average = self.tempo();
+                self.beats.set_tempo(average);
+                let mut accuracy = self.tempos.len() as f32 * frame_time / ACCURACY_TIME;
+                if accuracy > 1.0 {
+                    accuracy = 1.0;
+                }
+                self.events.push((time, Event::Tempo { average, accuracy }));
             }
         }
+
+        if transition == Some(silence::Transition::Reset) {
+            self.reset();
+            self.events.push((time, Event::Reset));
+        }
+        if self.silence.state() == silence::State::Silence {
+            self.rms_sma = SumTreeSMA::new();
+        }
+    }
+
+    /// Average of the tempos of the last frames.
+    fn tempo(&self) -> f32 {
+        self.tempos.iter().sum::<f32>() / self.tempos.len() as f32
+    }
+
+    fn reset(&mut self) {
+        self.bpm_detect = BPMDetect::new(1, self.sample_rate);
+        self.tempos.clear();
+        self.beats = beat::BeatTracker::default();
+        self.bars = beat::BarTracker::default();
+        self.grooves = groove::GrooveClassifier::default();
+        self.keys = key::KeyDetector::default();
+        self.sections = section::SectionDetector::new(self.frame_time());
+        self.agc = loudness::Agc::new(self.agc_config);
+    }
+}
+
+fn rms_of<'a>(samples: impl Iterator<Item = &'a f32>) -> f32 {
+    let (sum, len) = samples.fold((0.0, 0), |(sum, len), x| (sum + x * x, len + 1));
+    if len > 0 {
+        (sum / len as f32).sqrt()
+    } else {
+        0.0
     }
 }
 
-fn connect_and_init() -> anyhow::Result<(BufReader<UnixStream>, u16)> {
-    let socket_path = pulseaudio::socket_path_from_env().context("PulseAudio not available")?;
-    let mut sock = std::io::BufReader::new(UnixStream::connect(socket_path)?);
+/// Returns the balance and width of the first two channels of the interleaved `frame`.
+fn stereo(frame: &[f32], channels: usize) -> (f32, f32) {
+    let (mut left, mut right, mut mid, mut side) = (0.0, 0.0, 0.0, 0.0);
+    for s in frame.chunks_exact(channels) {
+        left += s[0] * s[0];
+        right += s[1] * s[1];
+        mid += (s[0] + s[1]) * (s[0] + s[1]) / 4.0;
+        side += (s[0] - s[1]) * (s[0] - s[1]) / 4.0;
+    }
+    let (left, right, mid, side): (f32, f32, f32, f32) =
+        (left.sqrt(), right.sqrt(), mid.sqrt(), side.sqrt());
 
-    let cookie = pulseaudio::cookie_path_from_env()
-        .and_then(|path| std::fs::read(path).ok())
-        .unwrap_or_default();
-    let auth = protocol::AuthParams {
-        version: protocol::MAX_VERSION,
-        supports_shm: false,
-        supports_memfd: false,
-        cookie,
+    let balance = if left + right > 0.0 {
+        (right - left) / (right + left)
+    } else {
+        0.0
+    };
+    let width = if mid + side > 0.0 {
+        side / (mid + side)
+    } else {
+        0.0
     };
+    (balance, width)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::audio_source::generator::{Generator, Signal};
 
-    protocol::write_command_message(
-        sock.get_mut(),
-        0,
-        protocol::Command::Auth(auth),
-        protocol::MAX_VERSION,
-    )?;
-
-    let (_, auth_reply) =
-        protocol::read_reply_message::<protocol::AuthReply>(&mut sock, protocol::MAX_VERSION)?;
-    let protocol_version = std::cmp::min(protocol::MAX_VERSION, auth_reply.version);
-
-    let mut props = protocol::Props::new();
-    props.set(
-        protocol::Prop::ApplicationName,
-        CString::new("pulseaudio-rs-playback").unwrap(),
-    );
-    protocol::write_command_message(
-        sock.get_mut(),
-        1,
-        protocol::Command::SetClientName(props),
-        protocol_version,
-    )?;
-
-    let _ =
-        protocol::read_reply_message::<protocol::SetClientNameReply>(&mut sock, protocol_version)?;
-    Ok((sock, protocol_version))
+    const RATE: u32 = 44100;
+    const CHUNK: usize = 1000; // Not a divisor of the frame size
+
+    fn click_track(bpm: f32, seconds: f32) -> Vec<f32> {
+        generate(Signal::Clicks { bpm }, seconds)
+    }
+
+    fn generate(signal: Signal, seconds: f32) -> Vec<f32> {
+        let mut generator = Generator::with_sample_rate(signal, RATE);
+        let len = (seconds * RATE as f32) as usize;
+        let mut samples = Vec::with_capacity(len);
+        while samples.len() < len {
+            generator.read(&mut samples).unwrap();
+        }
+        samples.truncate(len);
+        samples
+    }
+
+    fn analyzer() -> Analyzer {
+        Analyzer::new(RATE, 1, &Config::default())
+    }
+
+    fn tempo(bpm: f32) -> f32 {
+        let mut analyzer = analyzer();
+        let mut tempo = None;
+        for chunk in click_track(bpm, 40.0).chunks(CHUNK) {
+            for (_, event) in analyzer.process(chunk) {
+                if let Event::Tempo { average, .. } = event {
+                    tempo = Some(average);
+                }
+            }
+        }
+        tempo.expect("no tempo detected")
+    }
+
+    fn assert_tempo(bpm: f32) {
+        let detected = tempo(bpm);
+        assert!(
+            (detected - bpm).abs() < bpm * 0.03,
+            "detected {} BPM instead of {}",
+            detected,
+            bpm
+        );
+    }
+
+    #[test]
+    fn tempo_70() {
+        assert_tempo(70.0);
+    }
+
+    #[test]
+    fn tempo_90() {
+        assert_tempo(90.0);
+    }
+
+    #[test]
+    fn tempo_140() {
+        assert_tempo(140.0);
+    }
+
+    #[test]
+    fn tempo_174() {
+        assert_tempo(174.0);
+    }
+
+    #[test]
+    fn reset_after_silence() {
+        let mut analyzer = analyzer();
+        let music = click_track(120.0, 10.0);
+        for chunk in music.chunks(CHUNK) {
+            let events: Vec<Event> = analyzer.process(chunk).map(|(_, e)| e).collect();
+            assert!(!events.iter().any(|e| matches!(e, Event::Reset)));
+        }
+
+        let silence = vec![0.0; CHUNK];
+        let mut fed = 0;
+        loop {
+            let events: Vec<Event> = analyzer.process(&silence).map(|(_, e)| e).collect();
+            fed += CHUNK;
+            if events.iter().any(|e| matches!(e, Event::Reset)) {
+                break;
+            }
+            assert!(fed < 10 * RATE as usize, "no reset after silence");
+        }
+        assert!(fed as f32 / RATE as f32 >= silence::SilenceConfig::default().reset);
+    }
+
+    #[test]
+    fn breakdown_keeps_tempo() {
+        let mut analyzer = analyzer();
+        let mut events: Vec<Event> = Vec::new();
+        let track = click_track(120.0, 20.0);
+        let silence = vec![0.0; (1.5 * RATE as f32) as usize];
+        for chunk in track
+            .iter()
+            .chain(&silence)
+            .chain(&track)
+            .copied()
+            .collect::<Vec<_>>()
+            .chunks(CHUNK)
+        {
+            events.extend(analyzer.process(chunk).map(|(_, e)| e));
+        }
+
+        assert!(!events.iter().any(|e| matches!(e, Event::Reset)));
+        let breakdown = events
+            .iter()
+            .position(|e| matches!(e, Event::Breakdown { active: true }))
+            .expect("no breakdown");
+        let resume = events
+            .iter()
+            .position(|e| matches!(e, Event::Breakdown { active: false }))
+            .expect("no resume");
+        assert!(breakdown < resume);
+        let tempo = events[resume..]
+            .iter()
+            .find_map(|e| match e {
+                Event::Tempo { average, .. } => Some(*average),
+                _ => None,
+            })
+            .expect("no tempo after the breakdown");
+        assert!(
+            (tempo - 120.0).abs() < 120.0 * 0.03,
+            "tempo {} after the breakdown",
+            tempo
+        );
+    }
+
+    #[test]
+    fn section_at_join() {
+        let mut analyzer = analyzer();
+        let mut sections = Vec::new();
+        let mut signal = generate(Signal::PinkNoise, 30.0);
+        signal.extend(generate(Signal::Sine { frequency: 440.0 }, 30.0));
+        for chunk in signal.chunks(CHUNK) {
+            sections.extend(
+                analyzer
+                    .process(chunk)
+                    .filter(|(_, e)| matches!(e, Event::Section { .. })),
+            );
+        }
+
+        let [(time, _)] = sections[..] else {
+            panic!("sections {:?} instead of one at the join", sections);
+        };
+        // Found once the frames after the join fill the window compared, about 3 s later.
+        assert!((30.0..35.0).contains(&time), "section at {} s", time);
+    }
 }
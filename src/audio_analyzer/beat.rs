@@ -0,0 +1,356 @@
+// SPDX-License-Identifier: EUPL-1.2
+
+use std::collections::VecDeque;
+
+use super::spectrum::Spectrum;
+
+const THRESHOLD_TIME: f32 = 0.25; // seconds of flux history for the adaptive threshold
+const THRESHOLD_RATIO: f32 = 1.5; // Flux above the local mean needed for an onset
+const MIN_STRENGTH: f32 = 0.1; // Flux relative to the recent peak needed for an onset
+const MIN_INTERVAL: f64 = 0.05; // seconds between two onsets
+const PEAK_DECAY: f32 = 0.5; // Ratio the flux peak decays to in one second
+
+const TOLERANCE: f64 = 0.15; // Distance from the predicted beat to accept an onset, in beats
+const CORRECTION: f64 = 0.5; // Part of the phase error corrected on every beat
+
+#[derive(Debug, Clone, Copy)]
+pub struct Onset {
+    pub time: f64,
+    pub strength: f32,
+}
+
+/// Detects note onsets from the positive spectral flux of consecutive FFTs.
+#[derive(Default)]
+pub struct OnsetDetector {
+    band: Option<(f32, f32)>,
+    previous: Vec<f32>,
+    history: VecDeque<f32>,
+    flux: [f32; 2],
+    time: f64,
+    last_onset: f64,
+    peak: f32,
+}
+
+impl OnsetDetector {
+    /// Detects onsets between the `low` and `high` frequencies only, in Hz.
+    pub fn band(low: f32, high: f32) -> OnsetDetector {
+        OnsetDetector {
+            band: Some((low, high)),
+            ..Default::default()
+        }
+    }
+
+    /// Feeds the last FFT of `spectrum`. Onsets are found by peak picking, so they are reported one
+    /// FFT late.
+    pub fn process(&mut self, spectrum: &Spectrum) -> Option<Onset> {
+        let magnitudes = spectrum.magnitudes();
+        self.previous.resize(magnitudes.len(), 0.0);
+        let mut flux = 0.0;
+        for (bin, (magnitude, previous)) in
+            magnitudes.iter().zip(self.previous.iter_mut()).enumerate()
+        {
+            if let Some((low, high)) = self.band {
+                let frequency = spectrum.frequency(bin);
+                if frequency < low || frequency >= high {
+                    continue;
+                }
+            }
+            let magnitude = magnitude.ln_1p();
+            flux += (magnitude - *previous).max(0.0);
+            *previous = magnitude;
+        }
+
+        let hop_time = spectrum.hop_time();
+        self.peak = (self.peak * PEAK_DECAY.powf(hop_time)).max(flux);
+
+        let [before, candidate] = self.flux;
+        let mean = self.history.iter().sum::<f32>() / self.history.len().max(1) as f32;
+        let strength = if self.peak > 0.0 {
+            (candidate / self.peak).min(1.0)
+        } else {
+            0.0
+        };
+        let onset = (candidate > before
+            && candidate >= flux
+            && candidate > mean * THRESHOLD_RATIO
+            && strength > MIN_STRENGTH
+            && self.time - self.last_onset > MIN_INTERVAL)
+            .then_some(Onset {
+                time: self.time,
+                strength,
+            });
+        if let Some(onset) = onset {
+            self.last_onset = onset.time;
+        }
+
+        self.history.push_back(candidate);
+        while self.history.len() as f32 * hop_time > THRESHOLD_TIME {
+            self.history.pop_front();
+        }
+        self.flux = [candidate, flux];
+        self.time = spectrum.time();
+
+        onset
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct Beat {
+    pub time: f64,
+    pub strength: f32,
+    pub phase: f32,
+}
+
+/// Locks a beat grid at the detected tempo onto the onsets.
+#[derive(Default)]
+pub struct BeatTracker {
+    period: Option<f64>,
+    next: Option<f64>,
+}
+
+impl BeatTracker {
+    pub fn set_tempo(&mut self, bpm: f32) {
+        self.period = (bpm > 0.0).then(|| 60.0 / bpm as f64);
+    }
+
+    /// Duration of a beat in seconds, once the tempo is known.
+    pub fn period(&self) -> Option<f64> {
+        self.period
+    }
+
+    /// Returns the beat falling at `time`, if any. Onsets close to the predicted beat are taken as
+    /// the beat and pull the grid towards them, otherwise the beat is extrapolated from the grid
+    /// with a strength of 0.
+    pub fn process(&mut self, onset: Option<Onset>, time: f64) -> Option<Beat> {
+        let period = self.period?;
+
+        if let Some(onset) = onset {
+            let Some(next) = self.next else {
+                self.next = Some(onset.time + period);
+                return Some(Beat {
+                    time: onset.time,
+                    strength: onset.strength,
+                    phase: 0.0,
+                });
+            };
+            let error = (onset.time - next) / period;
+            if error.abs() < TOLERANCE {
+                self.next = Some(next + period + error * period * CORRECTION);
+                return Some(Beat {
+                    time: onset.time,
+                    strength: onset.strength,
+                    phase: error as f32,
+                });
+            }
+        }
+
+        let mut next = self.next?;
+        if time <= next + TOLERANCE * period {
+            return None;
+        }
+        // Skip the beats missed while no onset was around.
+        while time > next + period + TOLERANCE * period {
+            next += period;
+        }
+        self.next = Some(next + period);
+        Some(Beat {
+            time: next,
+            strength: 0.0,
+            phase: 0.0,
+        })
+    }
+}
+
+const METERS: [usize; 2] = [3, 4]; // Candidate beats per bar
+const ACCENT_DECAY: f32 = 0.95; // Per beat decay of the accent history
+const MIN_BARS: u64 = 2; // Bars of the longest meter needed before the first bar event
+const SWITCH_RATIO: f32 = 1.25; // Advantage needed to change the meter or the drop
+
+#[derive(Debug, Clone, Copy)]
+pub struct Bar {
+    pub index: u64,
+    pub beats_per_bar: u8,
+}
+
+/// Estimates the meter and the downbeats from the accent of every beat.
+///
+/// The strongest accent of the bar is taken as the drop. Following roots music, the drop of a four
+/// beat bar falls on beat 3 (as in one-drop, rockers and steppers); in a three beat bar it is
+/// the downbeat itself.
+#[derive(Default)]
+pub struct BarTracker {
+    beats: u64,
+    accents: [[f32; 4]; METERS.len()],
+    meter: usize,
+    drop: Option<usize>,
+    position: Option<(usize, usize)>,
+    index: u64,
+}
+
+impl BarTracker {
+    /// Position of the last beat in its bar, along with the number of beats per bar.
+    pub fn position(&self) -> Option<(usize, usize)> {
+        self.position
+    }
+
+    /// Feeds the accent of the next beat and returns the bar starting on it, if any.
+    pub fn process(&mut self, accent: f32) -> Option<Bar> {
+        for (accents, meter) in self.accents.iter_mut().zip(METERS) {
+            let position = (self.beats % meter as u64) as usize;
+            for a in accents[..meter].iter_mut() {
+                *a *= ACCENT_DECAY;
+            }
+            accents[position] += accent;
+        }
+        let beat = self.beats;
+        self.beats += 1;
+
+        if beat < MIN_BARS * METERS[METERS.len() - 1] as u64 {
+            return None;
+        }
+
+        // The meter whose strongest beat stands out the most from the others. Both the meter and
+        // the drop only change when a candidate is clearly better, otherwise the accent of the
+        // last beat would keep moving them around.
+        let contrasts: [f32; METERS.len()] =
+            std::array::from_fn(|i| contrast(&self.accents[i][..METERS[i]]));
+        let best = (0..METERS.len()).max_by(|&a, &b| contrasts[a].total_cmp(&contrasts[b]))?;
+        if contrasts[best] > contrasts[self.meter] * SWITCH_RATIO {
+            self.meter = best;
+            self.drop = None;
+        }
+        let (accents, meter) = (&self.accents[self.meter], METERS[self.meter]);
+
+        let best = (0..meter).max_by(|&a, &b| accents[a].total_cmp(&accents[b]))?;
+        let drop = match self.drop {
+            Some(drop) if accents[best] <= accents[drop] * SWITCH_RATIO => drop,
+            _ => best,
+        };
+        self.drop = Some(drop);
+        let downbeat = (drop + meter - if meter == 4 { 2 } else { 0 }) % meter;
+
+        let position = (beat as usize + meter - downbeat) % meter;
+        self.position = Some((position, meter));
+        if position != 0 {
+            return None;
+        }
+        let bar = Bar {
+            index: self.index,
+            beats_per_bar: meter as u8,
+        };
+        self.index += 1;
+        Some(bar)
+    }
+}
+
+fn contrast(accents: &[f32]) -> f32 {
+    let mean = accents.iter().sum::<f32>() / accents.len() as f32;
+    let max = accents.iter().copied().fold(0.0, f32::max);
+    if mean > 0.0 {
+        (max - mean) / mean
+    } else {
+        0.0
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::audio_source::generator::{Generator, Signal};
+    use crate::audio_source::AudioSource;
+
+    const RATE: u32 = 44100;
+
+    fn onset(time: f64) -> Option<Onset> {
+        Some(Onset {
+            time,
+            strength: 1.0,
+        })
+    }
+
+    #[test]
+    fn onsets_of_clicks() {
+        let mut generator = Generator::with_sample_rate(Signal::Clicks { bpm: 120.0 }, RATE);
+        let mut samples = Vec::new();
+        while samples.len() < 5 * RATE as usize {
+            generator.read(&mut samples).unwrap();
+        }
+        let mut spectrum = Spectrum::new(RATE);
+        let mut detector = OnsetDetector::default();
+        let mut onsets = Vec::new();
+        spectrum.input_samples(&samples, |spectrum| {
+            onsets.extend(detector.process(spectrum).map(|onset| onset.time));
+        });
+
+        // The first click starts the stream, and gives no onset.
+        assert_eq!(onsets.len(), 9, "onsets at {:?}", onsets);
+        for (beat, time) in (1..).zip(onsets) {
+            assert!(
+                (time - beat as f64 * 0.5).abs() < 2.0 * spectrum.hop_time() as f64,
+                "onset of beat {} at {}",
+                beat,
+                time
+            );
+        }
+    }
+
+    #[test]
+    fn beats_lock_onto_onsets() {
+        let mut tracker = BeatTracker::default();
+        assert!(tracker.process(onset(0.1), 0.1).is_none());
+        tracker.set_tempo(120.0);
+
+        let beat = tracker.process(onset(0.2), 0.2).unwrap();
+        assert_eq!((beat.time, beat.phase), (0.2, 0.0));
+        // An onset a tenth of a beat late is the beat, and moves the grid by half of that.
+        let beat = tracker.process(onset(0.75), 0.75).unwrap();
+        assert_eq!(beat.time, 0.75);
+        assert!((beat.phase - 0.1).abs() < 1e-6);
+        let beat = tracker.process(onset(1.25), 1.25).unwrap();
+        assert!((beat.phase - 0.05).abs() < 1e-6);
+        // Onsets off the grid are not beats.
+        assert!(tracker.process(onset(1.5), 1.5).is_none());
+    }
+
+    #[test]
+    fn beats_without_onsets() {
+        let mut tracker = BeatTracker::default();
+        tracker.set_tempo(120.0);
+        tracker.process(onset(0.0), 0.0).unwrap();
+        assert!(tracker.process(None, 0.55).is_none());
+        let beat = tracker.process(None, 0.6).unwrap();
+        assert_eq!((beat.time, beat.strength), (0.5, 0.0));
+        // The beats missed meanwhile are skipped.
+        let beat = tracker.process(None, 2.1).unwrap();
+        assert_eq!(beat.time, 2.0);
+    }
+
+    /// The bars found for beats with the accents of `bar` repeated.
+    fn bars(bar: &[f32]) -> Vec<(u64, Bar)> {
+        let mut tracker = BarTracker::default();
+        (0..24)
+            .filter_map(|beat| {
+                let accent = bar[beat as usize % bar.len()];
+                tracker.process(accent).map(|bar| (beat, bar))
+            })
+            .collect()
+    }
+
+    #[test]
+    fn meter_of_four() {
+        // Accented on beat 3, which makes the first beat the downbeat.
+        let bars = bars(&[0.2, 0.2, 1.0, 0.2]);
+        let beats: Vec<u64> = bars.iter().map(|(beat, _)| *beat).collect();
+        assert_eq!(beats, [8, 12, 16, 20]);
+        assert!(bars.iter().all(|(_, bar)| bar.beats_per_bar == 4));
+        assert_eq!(bars[3].1.index, 3);
+    }
+
+    #[test]
+    fn meter_of_three() {
+        let bars = bars(&[1.0, 0.2, 0.2]);
+        let beats: Vec<u64> = bars.iter().map(|(beat, _)| *beat).collect();
+        assert_eq!(beats, [9, 12, 15, 18, 21]);
+        assert!(bars.iter().all(|(_, bar)| bar.beats_per_bar == 3));
+    }
+}
@@ -0,0 +1,194 @@
+// SPDX-License-Identifier: EUPL-1.2
+
+use super::beat::OnsetDetector;
+use super::spectrum::Spectrum;
+
+const KICK: (f32, f32) = (30.0, 150.0); // Hz
+const SKANK: (f32, f32) = (500.0, 4000.0); // Hz
+const SLOTS: usize = 8; // Eighth notes of a four beat bar
+const DECAY: f32 = 0.8; // Per bar decay of the onset histograms
+const MIN_BARS: u32 = 2;
+
+/// Roots drum patterns, told apart by where the kick lands in a four beat bar.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Style {
+    /// Kick on beat 3 only, beat 1 is dropped.
+    OneDrop,
+    /// Kick on beats 1 and 3.
+    Rockers,
+    /// Kick on every beat.
+    Steppers,
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct Groove {
+    pub style: Style,
+    pub confidence: f32,
+    pub skank: f32,
+}
+
+/// Classifies the drum pattern from the kick onsets of the last bars, and measures how much the
+/// guitar and keys onsets fall on the offbeats (the skank).
+pub struct GrooveClassifier {
+    kick: OnsetDetector,
+    skank: OnsetDetector,
+    kicks: [f32; SLOTS],
+    skanks: [f32; SLOTS],
+    beat: Option<(f64, f64, usize)>,
+    bars: u32,
+}
+
+impl Default for GrooveClassifier {
+    fn default() -> GrooveClassifier {
+        GrooveClassifier {
+            kick: OnsetDetector::band(KICK.0, KICK.1),
+            skank: OnsetDetector::band(SKANK.0, SKANK.1),
+            kicks: [0.0; SLOTS],
+            skanks: [0.0; SLOTS],
+            beat: None,
+            bars: 0,
+        }
+    }
+}
+
+impl GrooveClassifier {
+    /// Moves the grid to the beat at `time`, lasting `period` seconds, at `position` of a bar of
+    /// `beats_per_bar` beats. Only four beat bars are classified.
+    pub fn beat(&mut self, time: f64, period: f64, position: usize, beats_per_bar: usize) {
+        self.beat = (beats_per_bar * 2 == SLOTS).then_some((time, period, position));
+    }
+
+    /// Feeds the last FFT of `spectrum`.
+    pub fn process(&mut self, spectrum: &Spectrum) {
+        let kick = self.kick.process(spectrum);
+        let skank = self.skank.process(spectrum);
+        let Some((time, period, position)) = self.beat else {
+            return;
+        };
+        let slot = |onset_time: f64| {
+            let half = ((onset_time - time) / period * 2.0).round() as isize;
+            (position as isize * 2 + half).rem_euclid(SLOTS as isize) as usize
+        };
+        if let Some(onset) = kick {
+            self.kicks[slot(onset.time)] += onset.strength;
+        }
+        if let Some(onset) = skank {
+            self.skanks[slot(onset.time)] += onset.strength;
+        }
+    }
+
+    /// Classifies the last bars, to be called on every downbeat.
+    pub fn bar(&mut self) -> Option<Groove> {
+        self.bars += 1;
+        let groove = if self.bars >= MIN_BARS {
+            classify(&self.kicks, &self.skanks)
+        } else {
+            None
+        };
+        for x in self.kicks.iter_mut().chain(self.skanks.iter_mut()) {
+            *x *= DECAY;
+        }
+        groove
+    }
+}
+
+fn classify(kicks: &[f32; SLOTS], skanks: &[f32; SLOTS]) -> Option<Groove> {
+    let max = kicks.iter().copied().fold(0.0, f32::max);
+    if max <= 0.0 {
+        return None;
+    }
+    // Kick on each beat, relative to the strongest slot.
+    let b: [f32; 4] = std::array::from_fn(|beat| kicks[beat * 2] / max);
+
+    let one_drop = b[2] * (1.0 - b[0]) * (1.0 - (b[1] + b[3]) / 2.0);
+    // The bar tracker may place the downbeat on either pair of alternate beats.
+    let rockers = (0..2)
+        .map(|p| b[p].min(b[p + 2]) * (1.0 - (b[1 - p] + b[3 - p]) / 2.0))
+        .fold(0.0, f32::max);
+    let steppers = b.iter().copied().fold(1.0, f32::min);
+
+    let mut scores = [
+        (Style::OneDrop, one_drop),
+        (Style::Rockers, rockers),
+        (Style::Steppers, steppers),
+    ];
+    scores.sort_by(|(_, a), (_, b)| b.total_cmp(a));
+    let [(style, best), (_, second), _] = scores;
+
+    let max = skanks.iter().copied().fold(0.0, f32::max);
+    let skank = if max > 0.0 {
+        let on_beat = (skanks[0] + skanks[4]) / 2.0;
+        let back_beat = (skanks[2] + skanks[6]) / 2.0;
+        let off_beat = (skanks[1] + skanks[3] + skanks[5] + skanks[7]) / 4.0;
+        ((back_beat.max(off_beat) - on_beat) / max).clamp(0.0, 1.0)
+    } else {
+        0.0
+    };
+
+    Some(Groove {
+        style,
+        confidence: best - second,
+        skank,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const RATE: u32 = 44100;
+    const PERIOD: f64 = 0.5; // 120 BPM
+
+    /// The groove of bars with a 60 Hz kick on the `kicked` beats, on a known beat grid.
+    fn groove(kicked: [bool; 4]) -> Groove {
+        let samples: Vec<f32> = (0..16 * RATE)
+            .map(|i| {
+                let t = i as f64 / RATE as f64;
+                let beat = (t / PERIOD) as usize;
+                let t = (t - beat as f64 * PERIOD) as f32;
+                if kicked[beat % 4] {
+                    0.8 * (2.0 * std::f32::consts::PI * 60.0 * t).sin() * (-t * 20.0).exp()
+                } else {
+                    0.0
+                }
+            })
+            .collect();
+
+        let mut spectrum = Spectrum::new(RATE);
+        let mut classifier = GrooveClassifier::default();
+        let mut last_beat = None;
+        let mut groove = None;
+        spectrum.input_samples(&samples, |spectrum| {
+            let beat = (spectrum.time() / PERIOD) as usize;
+            if last_beat != Some(beat) {
+                last_beat = Some(beat);
+                classifier.beat(beat as f64 * PERIOD, PERIOD, beat % 4, 4);
+                if beat.is_multiple_of(4) {
+                    groove = classifier.bar().or(groove);
+                }
+            }
+            classifier.process(spectrum);
+        });
+        groove.expect("no groove")
+    }
+
+    #[test]
+    fn one_drop() {
+        let groove = groove([false, false, true, false]);
+        assert_eq!(groove.style, Style::OneDrop);
+        assert!(groove.confidence > 0.5, "confidence {}", groove.confidence);
+    }
+
+    #[test]
+    fn rockers() {
+        let groove = groove([true, false, true, false]);
+        assert_eq!(groove.style, Style::Rockers);
+    }
+
+    #[test]
+    fn steppers() {
+        let groove = groove([true; 4]);
+        assert_eq!(groove.style, Style::Steppers);
+        assert!(groove.confidence > 0.5, "confidence {}", groove.confidence);
+    }
+}
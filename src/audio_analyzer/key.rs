@@ -0,0 +1,180 @@
+// SPDX-License-Identifier: EUPL-1.2
+
+use super::spectrum::Spectrum;
+
+/// Pitch classes from C to B.
+pub const PITCH_CLASSES: usize = 12;
+
+// The bins of the spectrum are about 21.5 Hz wide, as wide as a semitone at about 360 Hz. Below
+// that a bin spans several pitch classes, so the low notes of the bass and the chords only count
+// through their harmonics.
+const PITCH_MIN: f32 = 370.0; // Hz
+const PITCH_MAX: f32 = 4000.0; // Hz
+const CHROMA_TIME: f32 = 0.5; // Seconds over which the chromagram is smoothed
+const KEY_TIME: f32 = 8.0; // Seconds over which the key is estimated
+
+// Key profiles of Krumhansl and Kessler, from the tonic up.
+const MAJOR: [f32; PITCH_CLASSES] = [
+    6.35, 2.23, 3.48, 2.33, 4.38, 4.09, 2.52, 5.19, 2.39, 3.66, 2.29, 2.88,
+];
+const MINOR: [f32; PITCH_CLASSES] = [
+    6.33, 2.68, 3.52, 5.38, 2.60, 3.53, 2.54, 4.75, 3.98, 2.69, 3.34, 3.17,
+];
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Mode {
+    Major,
+    Minor,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Key {
+    /// Pitch class of the tonic, 0 for C.
+    pub tonic: u8,
+    pub mode: Mode,
+    /// Correlation of the chromagram with the key profile, 0.0 to 1.0.
+    pub confidence: f32,
+}
+
+impl Key {
+    /// Position on the circle of fifths from C major, 0 to 11. Relative keys share it.
+    pub fn fifths(&self) -> u8 {
+        let major = match self.mode {
+            Mode::Major => self.tonic,
+            Mode::Minor => (self.tonic + 3) % 12,
+        };
+        (major * 7) % 12
+    }
+}
+
+/// Folds the spectrum into a chromagram, and matches the chromagram of the last seconds against
+/// the major and minor key profiles.
+#[derive(Default)]
+pub struct KeyDetector {
+    classes: Vec<Option<usize>>,
+    chroma: [f32; PITCH_CLASSES],
+    profile: [f32; PITCH_CLASSES],
+}
+
+impl KeyDetector {
+    pub fn process(&mut self, spectrum: &Spectrum) {
+        let magnitudes = spectrum.magnitudes();
+        if self.classes.len() != magnitudes.len() {
+            self.classes = (0..magnitudes.len())
+                .map(|bin| {
+                    let frequency = spectrum.frequency(bin);
+                    (PITCH_MIN..PITCH_MAX).contains(&frequency).then(|| {
+                        let semitones = (12.0 * (frequency / 440.0).log2()).round() as i32;
+                        (semitones + 9).rem_euclid(12) as usize
+                    })
+                })
+                .collect();
+        }
+
+        let mut chroma = [0.0; PITCH_CLASSES];
+        for (class, magnitude) in self.classes.iter().zip(magnitudes) {
+            if let Some(class) = class {
+                chroma[*class] += magnitude * magnitude;
+            }
+        }
+        let total: f32 = chroma.iter().sum();
+        if total <= 0.0 {
+            return;
+        }
+
+        let smooth = |time: f32| 1.0 - (-spectrum.hop_time() / time).exp();
+        let (chroma_k, key_k) = (smooth(CHROMA_TIME), smooth(KEY_TIME));
+        for ((energy, chroma), profile) in
+            chroma.iter().zip(&mut self.chroma).zip(&mut self.profile)
+        {
+            let energy = energy / total;
+            *chroma += (energy - *chroma) * chroma_k;
+            *profile += (energy - *profile) * key_k;
+        }
+    }
+
+    /// Energy of the pitch classes, from C to B, relative to the strongest one.
+    pub fn chroma(&self) -> [f32; PITCH_CLASSES] {
+        let max = self.chroma.iter().copied().fold(0.0, f32::max);
+        if max > 0.0 {
+            self.chroma.map(|energy| energy / max)
+        } else {
+            self.chroma
+        }
+    }
+
+    /// The key that correlates best with the chromagram of the last seconds.
+    pub fn key(&self) -> Option<Key> {
+        let mut best: Option<Key> = None;
+        for tonic in 0..PITCH_CLASSES {
+            for (mode, profile) in [(Mode::Major, &MAJOR), (Mode::Minor, &MINOR)] {
+                let rotated: [f32; PITCH_CLASSES] =
+                    std::array::from_fn(|i| self.profile[(tonic + i) % PITCH_CLASSES]);
+                let confidence = correlation(&rotated, profile);
+                if best.is_none_or(|best| confidence > best.confidence) {
+                    best = Some(Key {
+                        tonic: tonic as u8,
+                        mode,
+                        confidence,
+                    });
+                }
+            }
+        }
+        best.filter(|key| key.confidence > 0.0)
+    }
+}
+
+/// Pearson correlation of two profiles.
+fn correlation(a: &[f32; PITCH_CLASSES], b: &[f32; PITCH_CLASSES]) -> f32 {
+    let mean = |x: &[f32; PITCH_CLASSES]| x.iter().sum::<f32>() / PITCH_CLASSES as f32;
+    let (mean_a, mean_b) = (mean(a), mean(b));
+    let (mut ab, mut aa, mut bb) = (0.0, 0.0, 0.0);
+    for (a, b) in a.iter().zip(b) {
+        ab += (a - mean_a) * (b - mean_b);
+        aa += (a - mean_a) * (a - mean_a);
+        bb += (b - mean_b) * (b - mean_b);
+    }
+    if aa > 0.0 && bb > 0.0 {
+        ab / (aa * bb).sqrt()
+    } else {
+        0.0
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const RATE: u32 = 44100;
+
+    fn key(frequencies: &[f32]) -> Key {
+        let samples: Vec<f32> = (0..10 * RATE)
+            .map(|i| {
+                let t = i as f32 / RATE as f32;
+                frequencies
+                    .iter()
+                    .map(|f| 0.2 * (2.0 * std::f32::consts::PI * f * t).sin())
+                    .sum()
+            })
+            .collect();
+        let mut spectrum = Spectrum::new(RATE);
+        let mut detector = KeyDetector::default();
+        spectrum.input_samples(&samples, |spectrum| detector.process(spectrum));
+        detector.key().expect("no key detected")
+    }
+
+    #[test]
+    fn c_major_triad() {
+        // C6, E6 and G6
+        let key = key(&[1046.5, 1318.5, 1568.0]);
+        assert_eq!((key.tonic, key.mode), (0, Mode::Major));
+    }
+
+    #[test]
+    fn a_minor_triad() {
+        // A5, C6 and E6, the same pitch classes but for G
+        let key = key(&[880.0, 1046.5, 1318.5]);
+        assert_eq!((key.tonic, key.mode), (9, Mode::Minor));
+        assert_eq!(key.fifths(), 0);
+    }
+}
@@ -0,0 +1,286 @@
+// SPDX-License-Identifier: EUPL-1.2
+
+use std::collections::VecDeque;
+use std::f64::consts::PI;
+
+const BLOCK_TIME: f64 = 0.1; // Seconds per block of mean squares
+const MOMENTARY_BLOCKS: usize = 4; // 400 ms
+const SHORT_TERM_BLOCKS: usize = 30; // 3 s
+
+/// Loudness of silence, and the absolute gate of BS.1770.
+pub const LUFS_MIN: f32 = -70.0;
+
+/// Second order IIR filter, in direct form I.
+#[derive(Debug, Clone, Copy, Default)]
+struct Biquad {
+    b: [f64; 3],
+    a: [f64; 3],
+    x: [f64; 2],
+    y: [f64; 2],
+}
+
+impl Biquad {
+    fn process(&mut self, x: f64) -> f64 {
+        let y = self.b[0] * x + self.b[1] * self.x[0] + self.b[2] * self.x[1]
+            - self.a[1] * self.y[0]
+            - self.a[2] * self.y[1];
+        self.x = [x, self.x[0]];
+        self.y = [y, self.y[0]];
+        y
+    }
+}
+
+/// The K-weighting of BS.1770: a high shelf for the head, then a high pass, with the coefficients
+/// derived for any sample rate as in libebur128.
+#[derive(Debug, Clone, Copy)]
+struct KWeighting {
+    shelf: Biquad,
+    high_pass: Biquad,
+}
+
+impl KWeighting {
+    fn new(sample_rate: u32) -> KWeighting {
+        let fs = sample_rate as f64;
+
+        let (f0, gain, q) = (1681.974450955533, 3.999843853973347, 0.7071752369554196);
+        let k = (PI * f0 / fs).tan();
+        let vh = 10f64.powf(gain / 20.0);
+        let vb = vh.powf(0.4996667741545416);
+        let a0 = 1.0 + k / q + k * k;
+        let shelf = Biquad {
+            b: [
+                (vh + vb * k / q + k * k) / a0,
+                2.0 * (k * k - vh) / a0,
+                (vh - vb * k / q + k * k) / a0,
+            ],
+            a: [1.0, 2.0 * (k * k - 1.0) / a0, (1.0 - k / q + k * k) / a0],
+            ..Default::default()
+        };
+
+        let (f0, q) = (38.13547087602444, 0.5003270373238773);
+        let k = (PI * f0 / fs).tan();
+        let a0 = 1.0 + k / q + k * k;
+        let high_pass = Biquad {
+            b: [1.0, -2.0, 1.0],
+            a: [1.0, 2.0 * (k * k - 1.0) / a0, (1.0 - k / q + k * k) / a0],
+            ..Default::default()
+        };
+
+        KWeighting { shelf, high_pass }
+    }
+
+    fn process(&mut self, x: f32) -> f64 {
+        self.high_pass.process(self.shelf.process(x as f64))
+    }
+}
+
+/// Momentary (400 ms) and short-term (3 s) loudness in LUFS, per ITU-R BS.1770. All channels
+/// have a weight of 1, as the layout of the source is not known.
+pub struct Loudness {
+    filters: Vec<KWeighting>,
+    block_len: usize,
+    pending: usize,
+    sums: Vec<f64>,
+    blocks: VecDeque<Vec<f64>>,
+}
+
+impl Loudness {
+    pub fn new(sample_rate: u32, channels: usize) -> Loudness {
+        Loudness {
+            filters: vec![KWeighting::new(sample_rate); channels],
+            block_len: (BLOCK_TIME * sample_rate as f64).round() as usize,
+            pending: 0,
+            sums: vec![0.0; channels],
+            blocks: VecDeque::with_capacity(SHORT_TERM_BLOCKS + 1),
+        }
+    }
+
+    /// Feeds interleaved samples.
+    pub fn input_samples(&mut self, samples: &[f32]) {
+        for frame in samples.chunks_exact(self.filters.len()) {
+            for ((filter, sum), &x) in self.filters.iter_mut().zip(&mut self.sums).zip(frame) {
+                let y = filter.process(x);
+                *sum += y * y;
+            }
+            self.pending += 1;
+            if self.pending == self.block_len {
+                // The oldest block is reused once the short-term window is full.
+                let mut block = if self.blocks.len() == SHORT_TERM_BLOCKS {
+                    self.blocks.pop_front().unwrap()
+                } else {
+                    Vec::with_capacity(self.sums.len())
+                };
+                block.clear();
+                block.extend(self.sums.iter().map(|sum| sum / self.block_len as f64));
+                self.blocks.push_back(block);
+                self.sums.fill(0.0);
+                self.pending = 0;
+            }
+        }
+    }
+
+    /// Loudness of the last 100 ms block, for a quick response.
+    pub fn fast(&self) -> f32 {
+        self.loudness(1, None)
+    }
+
+    pub fn momentary(&self) -> f32 {
+        self.loudness(MOMENTARY_BLOCKS, None)
+    }
+
+    pub fn short_term(&self) -> f32 {
+        self.loudness(SHORT_TERM_BLOCKS, None)
+    }
+
+    /// Momentary loudness of a single channel, as if it was the only one.
+    pub fn channel_momentary(&self, channel: usize) -> f32 {
+        self.loudness(MOMENTARY_BLOCKS, Some(channel))
+    }
+
+    fn loudness(&self, blocks: usize, channel: Option<usize>) -> f32 {
+        let blocks = self.blocks.iter().rev().take(blocks);
+        let len = blocks.len();
+        if len == 0 {
+            return LUFS_MIN;
+        }
+        let power: f64 = blocks
+            .map(|block| match channel {
+                Some(channel) => block[channel],
+                None => block.iter().sum(),
+            })
+            .sum::<f64>()
+            / len as f64;
+        ((-0.691 + 10.0 * power.log10()) as f32).max(LUFS_MIN)
+    }
+}
+
+/// Attack and release times of the automatic gain control, in seconds.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct AgcConfig {
+    pub attack: f32,
+    pub release: f32,
+}
+
+impl Default for AgcConfig {
+    fn default() -> Self {
+        AgcConfig {
+            attack: 0.1,
+            release: 5.0,
+        }
+    }
+}
+
+const RANGE_MIN: f32 = 6.0; // LU, keeps steady sources from swinging over the full range
+
+/// Maps loudness to an intensity in 0..1 over the recent loudness range of the source, so that
+/// quiet and loud masters both use the whole range. The range follows the peaks and the dips of
+/// the loudness with the attack time when it widens and the release time when it narrows.
+pub struct Agc {
+    config: AgcConfig,
+    range: Option<(f32, f32)>,
+}
+
+impl Agc {
+    pub fn new(config: AgcConfig) -> Agc {
+        Agc {
+            config,
+            range: None,
+        }
+    }
+
+    /// Follows `lufs` for `time` seconds and returns its intensity. Loudness under the absolute
+    /// gate leaves the range untouched.
+    pub fn process(&mut self, lufs: f32, time: f32) -> f32 {
+        if lufs > LUFS_MIN {
+            let follow = |level: f32, widen: bool| {
+                let tau = if widen {
+                    self.config.attack
+                } else {
+                    self.config.release
+                };
+                level + (lufs - level) * (1.0 - (-time / tau.max(f32::EPSILON)).exp())
+            };
+            self.range = Some(match self.range {
+                None => (lufs, lufs),
+                Some((low, high)) => (follow(low, lufs < low), follow(high, lufs > high)),
+            });
+        }
+        self.intensity(lufs)
+    }
+
+    /// The intensity of `lufs` in the current range, without following it.
+    pub fn intensity(&self, lufs: f32) -> f32 {
+        let Some((low, high)) = self.range else {
+            return 0.0;
+        };
+        let span = (high - low).max(RANGE_MIN);
+        let floor = (high - span).max(LUFS_MIN);
+        ((lufs - floor) / (high - floor)).clamp(0.0, 1.0)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const RATE: u32 = 48000;
+
+    /// `seconds` of a 1 kHz sine of `dbfs` peak level, in all of `channels`.
+    fn sine(dbfs: f32, channels: usize, seconds: f32) -> Vec<f32> {
+        let amplitude = 10f32.powf(dbfs / 20.0);
+        (0..(seconds * RATE as f32) as usize)
+            .flat_map(|i| {
+                let x = (2.0 * std::f32::consts::PI * 1000.0 * i as f32 / RATE as f32).sin();
+                std::iter::repeat_n(amplitude * x, channels)
+            })
+            .collect()
+    }
+
+    fn loudness(samples: &[f32], channels: usize) -> Loudness {
+        let mut loudness = Loudness::new(RATE, channels);
+        loudness.input_samples(samples);
+        loudness
+    }
+
+    fn assert_lufs(lufs: f32, expected: f32) {
+        assert!(
+            (lufs - expected).abs() < 0.1,
+            "{} LUFS instead of {}",
+            lufs,
+            expected
+        );
+    }
+
+    #[test]
+    fn stereo_sine() {
+        let loudness = loudness(&sine(-23.0, 2, 4.0), 2);
+        assert_lufs(loudness.fast(), -23.0);
+        assert_lufs(loudness.momentary(), -23.0);
+        assert_lufs(loudness.short_term(), -23.0);
+        assert_lufs(loudness.channel_momentary(0), -26.0);
+        assert_lufs(loudness.channel_momentary(1), -26.0);
+    }
+
+    #[test]
+    fn mono_sine() {
+        let loudness = loudness(&sine(-20.0, 1, 1.0), 1);
+        assert_lufs(loudness.momentary(), -23.0);
+    }
+
+    #[test]
+    fn gated() {
+        assert_eq!(Loudness::new(RATE, 2).momentary(), LUFS_MIN);
+        assert_eq!(loudness(&sine(-80.0, 2, 1.0), 2).momentary(), LUFS_MIN);
+        assert_eq!(loudness(&vec![0.0; RATE as usize], 1).momentary(), LUFS_MIN);
+    }
+
+    #[test]
+    fn agc_skips_gated() {
+        let mut agc = Agc::new(AgcConfig::default());
+        assert_eq!(agc.process(LUFS_MIN, 1.0), 0.0);
+        assert_eq!(agc.process(-20.0, 1.0), 1.0);
+        agc.process(LUFS_MIN, 60.0);
+        assert_eq!(agc.intensity(-20.0), 1.0);
+        assert_eq!(agc.intensity(-23.0), 0.5);
+    }
+}
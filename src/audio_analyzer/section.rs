@@ -0,0 +1,199 @@
+// SPDX-License-Identifier: EUPL-1.2
+
+use std::collections::VecDeque;
+
+use super::key::PITCH_CLASSES;
+use super::spectrum::BANDS;
+use super::timbre::Timbre;
+
+const WINDOW_TIME: f32 = 3.0; // Seconds compared on each side of a boundary
+const MIN_LENGTH_TIME: f32 = 6.0; // Seconds between two boundaries
+const HISTORY_TIME: f32 = 24.0; // Seconds of novelty for the adaptive threshold
+const THRESHOLD: f32 = 1.5; // Standard deviations over the mean novelty for a boundary
+const NOVELTY_MIN: f32 = 0.1;
+const CENTROID_MAX: f32 = 5000.0; // Hz
+const TRACK_TIME: f32 = 24.0; // Seconds over which the averages of the track are taken
+
+const BREAKDOWN_VOLUME: f32 = 0.35;
+const DROP_VOLUME: f32 = 0.6;
+const DUB_BASS: f32 = 0.5;
+const DUB_UPPER: f32 = 0.6; // Upper bands over bass under which only the riddim plays
+const CHORUS_VOLUME: f32 = 0.1; // Over the average volume of the track
+
+const DIMENSIONS: usize = BANDS + PITCH_CLASSES + 3;
+const VOLUME: usize = DIMENSIONS - 1;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Kind {
+    Intro,
+    Verse,
+    Chorus,
+    /// Just drum and bass, the riddim of a dub version.
+    Dub,
+    Breakdown,
+    /// Back to full power after a breakdown.
+    Drop,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Section {
+    pub kind: Kind,
+    pub confidence: f32,
+}
+
+/// What the segmentation looks at in a frame.
+pub struct Features {
+    pub bands: [f32; BANDS],
+    pub chroma: [f32; PITCH_CLASSES],
+    pub timbre: Timbre,
+    /// Intensity, as in `Event::Volume`.
+    pub volume: f32,
+}
+
+impl Features {
+    /// The features as a vector, each group weighted for its number of dimensions so that the
+    /// twelve chroma bins do not outweigh the rest.
+    fn vector(&self) -> [f32; DIMENSIONS] {
+        let bands = 1.0 / (BANDS as f32).sqrt();
+        let chroma = 1.0 / (PITCH_CLASSES as f32).sqrt();
+        let timbre = 1.0 / 3f32.sqrt();
+        let mut vector = [0.0; DIMENSIONS];
+        for (v, band) in vector.iter_mut().zip(self.bands) {
+            *v = band * bands;
+        }
+        for (v, bin) in vector[BANDS..].iter_mut().zip(self.chroma) {
+            *v = bin * chroma;
+        }
+        vector[BANDS + PITCH_CLASSES] = (self.timbre.centroid / CENTROID_MAX).min(1.0) * timbre;
+        vector[BANDS + PITCH_CLASSES + 1] = self.timbre.flatness * timbre;
+        vector[VOLUME] = self.volume;
+        vector
+    }
+}
+
+/// Finds section boundaries as peaks of the novelty between the features of the frames before
+/// and after, and guesses the kind of the new section from its levels.
+pub struct SectionDetector {
+    window: usize,
+    min_length: usize,
+    history: usize,
+    track_k: f32,
+    frames: VecDeque<[f32; DIMENSIONS]>,
+    novelty: VecDeque<f32>,
+    length: usize,
+    track_volume: Option<f32>,
+    kind: Option<Kind>,
+}
+
+impl SectionDetector {
+    /// A detector for frames of `frame_time` seconds.
+    pub fn new(frame_time: f32) -> SectionDetector {
+        let frames = |time: f32| ((time / frame_time).round() as usize).max(1);
+        SectionDetector {
+            window: frames(WINDOW_TIME),
+            min_length: frames(MIN_LENGTH_TIME),
+            history: frames(HISTORY_TIME),
+            track_k: 1.0 - (-frame_time / TRACK_TIME).exp(),
+            frames: VecDeque::new(),
+            novelty: VecDeque::new(),
+            length: 0,
+            track_volume: None,
+            kind: None,
+        }
+    }
+
+    /// Follows the features of the next frame. Returns the section starting, if any, about
+    /// `WINDOW_TIME` late. The first frame starts the intro, which is not returned as there is
+    /// no boundary before it.
+    pub fn process(&mut self, features: &Features) -> Option<Section> {
+        let vector = features.vector();
+        let track_volume = self.track_volume.get_or_insert(features.volume);
+        *track_volume += (features.volume - *track_volume) * self.track_k;
+
+        self.frames.push_back(vector);
+        if self.frames.len() > 2 * self.window {
+            self.frames.pop_front();
+        }
+        self.length += 1;
+        if self.kind.is_none() {
+            self.start(Kind::Intro, 1.0);
+            return None;
+        }
+        if self.frames.len() < 2 * self.window {
+            return None;
+        }
+
+        let before = mean(self.frames.range(..self.window));
+        let after = mean(self.frames.range(self.window..));
+        let novelty = distance(&before, &after);
+
+        // The previous novelty is a peak if it beats both neighbours and the recent ones.
+        let threshold = self.threshold();
+        let len = self.novelty.len();
+        let peak = (len >= 2)
+            .then(|| self.novelty[len - 1])
+            .filter(|&previous| {
+                previous > self.novelty[len - 2] && previous >= novelty && previous > threshold
+            });
+
+        self.novelty.push_back(novelty);
+        if self.novelty.len() > self.history {
+            self.novelty.pop_front();
+        }
+
+        let peak = peak.filter(|_| self.length >= self.min_length)?;
+        let kind = self.classify(&after);
+        Some(self.start(kind, (1.0 - threshold / peak).clamp(0.0, 1.0)))
+    }
+
+    fn start(&mut self, kind: Kind, confidence: f32) -> Section {
+        self.kind = Some(kind);
+        self.length = 0;
+        Section { kind, confidence }
+    }
+
+    fn threshold(&self) -> f32 {
+        let len = self.novelty.len().max(1) as f32;
+        let mean = self.novelty.iter().sum::<f32>() / len;
+        let variance = self.novelty.iter().map(|n| (n - mean).powi(2)).sum::<f32>() / len;
+        (mean + THRESHOLD * variance.sqrt()).max(NOVELTY_MIN)
+    }
+
+    fn classify(&self, features: &[f32; DIMENSIONS]) -> Kind {
+        let band = |band: usize| features[band] * (BANDS as f32).sqrt();
+        let bass = (band(0) + band(1)) / 2.0;
+        let upper = (band(2) + band(3) + band(4)) / 3.0;
+        let volume = features[VOLUME];
+
+        if volume < BREAKDOWN_VOLUME {
+            Kind::Breakdown
+        } else if self.kind == Some(Kind::Breakdown) && volume > DROP_VOLUME {
+            Kind::Drop
+        } else if bass > DUB_BASS && upper < bass * DUB_UPPER {
+            Kind::Dub
+        } else if volume > self.track_volume.unwrap_or(0.0) + CHORUS_VOLUME {
+            Kind::Chorus
+        } else {
+            Kind::Verse
+        }
+    }
+}
+
+fn mean<'a>(frames: impl ExactSizeIterator<Item = &'a [f32; DIMENSIONS]>) -> [f32; DIMENSIONS] {
+    let len = frames.len().max(1) as f32;
+    let mut mean = [0.0; DIMENSIONS];
+    for frame in frames {
+        for (m, v) in mean.iter_mut().zip(frame) {
+            *m += v / len;
+        }
+    }
+    mean
+}
+
+fn distance(a: &[f32; DIMENSIONS], b: &[f32; DIMENSIONS]) -> f32 {
+    a.iter()
+        .zip(b)
+        .map(|(a, b)| (a - b).powi(2))
+        .sum::<f32>()
+        .sqrt()
+}
@@ -0,0 +1,193 @@
+// SPDX-License-Identifier: EUPL-1.2
+
+const ENTER_MARGIN: f32 = 2.0; // Calibrated enter threshold over the noise floor
+const EXIT_MARGIN: f32 = 4.0; // Calibrated exit threshold over the noise floor
+
+/// Silence detection settings. Levels are RMS, times are in seconds.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct SilenceConfig {
+    /// Level under which the source turns quiet.
+    pub enter: f32,
+    /// Level over which a quiet source plays again, at least `enter`.
+    pub exit: f32,
+    /// Quiet time before a breakdown, which keeps the tempo.
+    pub breakdown: f32,
+    /// Quiet time before the analysis is reset.
+    pub reset: f32,
+    /// Time spent measuring the noise floor of the source at startup, 0 to keep the thresholds.
+    pub calibrate: f32,
+}
+
+impl Default for SilenceConfig {
+    fn default() -> Self {
+        SilenceConfig {
+            enter: 0.01,
+            exit: 0.02,
+            breakdown: 0.618,
+            reset: 3.0,
+            calibrate: 0.0,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum State {
+    Sound,
+    /// Quiet for a while, like the break of a dub track: the tempo is kept.
+    Breakdown,
+    /// Quiet for long enough to start over.
+    Silence,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Transition {
+    Breakdown,
+    Resume,
+    Reset,
+}
+
+/// Tells sound from silence with separate enter and exit levels, so that echo tails hovering
+/// around a single threshold do not flap between the two.
+pub struct SilenceDetector {
+    state: State,
+    enter: f32,
+    exit: f32,
+    quiet: usize,
+    breakdown_len: usize,
+    reset_len: usize,
+    calibration: Option<(usize, f32)>,
+}
+
+impl SilenceDetector {
+    pub fn new(config: SilenceConfig, sample_rate: u32) -> SilenceDetector {
+        let len = |time: f32| (time * sample_rate as f32).floor() as usize;
+        SilenceDetector {
+            state: State::Sound,
+            enter: config.enter,
+            exit: config.exit.max(config.enter),
+            quiet: 0,
+            breakdown_len: len(config.breakdown),
+            reset_len: len(config.reset),
+            calibration: (config.calibrate > 0.0).then(|| (len(config.calibrate), f32::MAX)),
+        }
+    }
+
+    pub fn state(&self) -> State {
+        self.state
+    }
+
+    /// Follows the level `rms` of the next `len` samples per channel.
+    pub fn process(&mut self, rms: f32, len: usize) -> Option<Transition> {
+        self.calibrate(rms, len);
+
+        let loud = match self.state {
+            State::Sound => rms >= self.enter,
+            State::Breakdown | State::Silence => rms > self.exit,
+        };
+        if loud {
+            self.quiet = 0;
+            let resumed = self.state == State::Breakdown;
+            self.state = State::Sound;
+            return resumed.then_some(Transition::Resume);
+        }
+
+        self.quiet = self.quiet.saturating_add(len);
+        match self.state {
+            State::Sound | State::Breakdown if self.quiet >= self.reset_len => {
+                self.state = State::Silence;
+                Some(Transition::Reset)
+            }
+            State::Sound if self.quiet >= self.breakdown_len => {
+                self.state = State::Breakdown;
+                Some(Transition::Breakdown)
+            }
+            _ => None,
+        }
+    }
+
+    /// Raises the thresholds over the quietest level seen while calibrating, for sources with a
+    /// noise floor like a turntable.
+    fn calibrate(&mut self, rms: f32, len: usize) {
+        let Some((remaining, floor)) = self.calibration.as_mut() else {
+            return;
+        };
+        *floor = floor.min(rms);
+        *remaining = remaining.saturating_sub(len);
+        if *remaining == 0 {
+            let floor = *floor;
+            self.calibration = None;
+            self.enter = self.enter.max(floor * ENTER_MARGIN);
+            self.exit = self.exit.max(floor * EXIT_MARGIN);
+            eprintln!(
+                "noise floor: {}, silence under {} until over {}",
+                floor, self.enter, self.exit
+            );
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const RATE: u32 = 1000;
+
+    fn detector(calibrate: f32) -> SilenceDetector {
+        let config = SilenceConfig {
+            breakdown: 1.0,
+            reset: 3.0,
+            calibrate,
+            ..Default::default()
+        };
+        SilenceDetector::new(config, RATE)
+    }
+
+    #[test]
+    fn hysteresis() {
+        let mut detector = detector(0.0);
+        // Under the enter level, but not for long enough.
+        assert_eq!(detector.process(0.009, 999), None);
+        assert_eq!(detector.state(), State::Sound);
+        // Between the two levels: loud while sounding, the quiet time starts over.
+        assert_eq!(detector.process(0.015, 500), None);
+        assert_eq!(detector.process(0.009, 999), None);
+        assert_eq!(detector.process(0.009, 1), Some(Transition::Breakdown));
+        assert_eq!(detector.state(), State::Breakdown);
+        // Between the two levels: still quiet once in a breakdown.
+        assert_eq!(detector.process(0.015, 1000), None);
+        assert_eq!(detector.process(0.02, 100), None);
+        assert_eq!(detector.process(0.021, 100), Some(Transition::Resume));
+        assert_eq!(detector.state(), State::Sound);
+    }
+
+    #[test]
+    fn reset() {
+        let mut detector = detector(0.0);
+        assert_eq!(detector.process(0.0, 1000), Some(Transition::Breakdown));
+        assert_eq!(detector.process(0.0, 1999), None);
+        assert_eq!(detector.process(0.0, 1), Some(Transition::Reset));
+        assert_eq!(detector.state(), State::Silence);
+        assert_eq!(detector.process(0.0, 10000), None);
+        // Sound after a reset is not the end of a breakdown.
+        assert_eq!(detector.process(0.5, 100), None);
+        assert_eq!(detector.state(), State::Sound);
+    }
+
+    #[test]
+    fn reset_at_once() {
+        let mut detector = detector(0.0);
+        assert_eq!(detector.process(0.0, 3000), Some(Transition::Reset));
+    }
+
+    #[test]
+    fn calibration() {
+        let mut detector = detector(1.0);
+        // A noise floor of 0.02 moves the levels to 0.04 and 0.08.
+        assert_eq!(detector.process(0.1, 500), None);
+        assert_eq!(detector.process(0.02, 500), None);
+        assert_eq!(detector.process(0.039, 1000), Some(Transition::Breakdown));
+        assert_eq!(detector.process(0.08, 100), None);
+        assert_eq!(detector.process(0.081, 100), Some(Transition::Resume));
+        assert_eq!(detector.process(0.04, 1000), None);
+    }
+}
@@ -0,0 +1,136 @@
+// SPDX-License-Identifier: EUPL-1.2
+
+use super::spectrum::Spectrum;
+
+const FREQUENCY_MIN: f32 = 20.0; // Hz, leaves out DC and rumble
+const ROLLOFF: f32 = 0.85; // Share of the energy under the rolloff frequency
+const SMOOTH_TIME: f32 = 0.25; // Seconds over which the descriptors are smoothed
+
+/// Spectral descriptors of the timbre.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct Timbre {
+    /// Center of mass of the spectrum in Hz: the brightness.
+    pub centroid: f32,
+    /// Geometric over arithmetic mean of the power, from 0.0 (tonal) to 1.0 (noise).
+    pub flatness: f32,
+    /// Frequency in Hz under which 85% of the energy lies.
+    pub rolloff: f32,
+    /// Rise of the magnitudes since the previous spectrum, relative to their sum.
+    pub flux: f32,
+}
+
+/// Computes the timbre descriptors of every spectrum and smooths them.
+#[derive(Default)]
+pub struct TimbreAnalyzer {
+    previous: Vec<f32>,
+    timbre: Timbre,
+}
+
+impl TimbreAnalyzer {
+    pub fn process(&mut self, spectrum: &Spectrum) {
+        let magnitudes = spectrum.magnitudes();
+        let first = (0..magnitudes.len())
+            .find(|&bin| spectrum.frequency(bin) >= FREQUENCY_MIN)
+            .unwrap_or(magnitudes.len());
+        let bins = &magnitudes[first..];
+        self.previous.resize(bins.len(), 0.0);
+
+        let power: f32 = bins.iter().map(|m| m * m).sum();
+        let sum: f32 = bins.iter().sum();
+        if power <= 0.0 || bins.is_empty() {
+            return;
+        }
+
+        let frequency = |i: usize| spectrum.frequency(first + i);
+        let centroid = bins
+            .iter()
+            .enumerate()
+            .map(|(i, m)| frequency(i) * m * m)
+            .sum::<f32>()
+            / power;
+
+        let log_mean = bins.iter().map(|m| (m * m + 1e-12).ln()).sum::<f32>() / bins.len() as f32;
+        let flatness = (log_mean.exp() / (power / bins.len() as f32)).min(1.0);
+
+        let mut energy = 0.0;
+        let rolloff = bins
+            .iter()
+            .position(|m| {
+                energy += m * m;
+                energy >= ROLLOFF * power
+            })
+            .map_or(0.0, frequency);
+
+        let rise: f32 = bins
+            .iter()
+            .zip(&self.previous)
+            .map(|(m, previous)| (m - previous).max(0.0))
+            .sum();
+        let flux = rise / sum;
+        self.previous.copy_from_slice(bins);
+
+        let k = 1.0 - (-spectrum.hop_time() / SMOOTH_TIME).exp();
+        let smooth = |value: &mut f32, target: f32| *value += (target - *value) * k;
+        smooth(&mut self.timbre.centroid, centroid);
+        smooth(&mut self.timbre.flatness, flatness);
+        smooth(&mut self.timbre.rolloff, rolloff);
+        smooth(&mut self.timbre.flux, flux);
+    }
+
+    pub fn timbre(&self) -> Timbre {
+        self.timbre
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rand::{rngs::SmallRng, Rng, SeedableRng};
+
+    const RATE: u32 = 44100;
+
+    fn timbre(samples: &[f32]) -> Timbre {
+        let mut spectrum = Spectrum::new(RATE);
+        let mut analyzer = TimbreAnalyzer::default();
+        spectrum.input_samples(samples, |spectrum| analyzer.process(spectrum));
+        analyzer.timbre()
+    }
+
+    fn sine(frequency: f32) -> Vec<f32> {
+        (0..2 * RATE)
+            .map(|i| {
+                let t = i as f32 / RATE as f32;
+                0.5 * (2.0 * std::f32::consts::PI * frequency * t).sin()
+            })
+            .collect()
+    }
+
+    #[test]
+    fn centroid_of_sine() {
+        for frequency in [440.0, 2000.0, 5000.0] {
+            let timbre = timbre(&sine(frequency));
+            assert!(
+                (timbre.centroid - frequency).abs() < frequency * 0.02,
+                "centroid {} of {} Hz",
+                timbre.centroid,
+                frequency
+            );
+            assert!((timbre.rolloff - frequency).abs() < 25.0);
+        }
+    }
+
+    #[test]
+    fn flatness() {
+        let mut rng = SmallRng::seed_from_u64(0);
+        let noise: Vec<f32> = (0..2 * RATE).map(|_| rng.random_range(-0.5..0.5)).collect();
+        let noise = timbre(&noise);
+        let sine = timbre(&sine(1000.0));
+        assert!(noise.flatness > 0.4, "flatness {} of noise", noise.flatness);
+        assert!(sine.flatness < 0.01, "flatness {} of a sine", sine.flatness);
+        assert!(
+            noise.centroid > 8000.0,
+            "centroid {} of noise",
+            noise.centroid
+        );
+    }
+}
@@ -0,0 +1,137 @@
+// SPDX-License-Identifier: EUPL-1.2
+
+use std::path::PathBuf;
+use std::time::{Duration, Instant};
+
+use crate::audio_analyzer::{Config, Stop};
+
+pub mod generator;
+pub mod pcm;
+#[cfg(feature = "pipewire")]
+pub mod pipewire;
+pub mod pulse;
+pub mod radio;
+pub mod wav;
+
+const CHUNK: usize = 1024; // Frames read at once by sources that are not live
+
+/// A stream of interleaved `f32` samples in `-1.0..1.0`.
+pub trait AudioSource: Send {
+    fn sample_rate(&self) -> u32;
+
+    fn channels(&self) -> usize;
+
+    /// Appends the next samples to `out`. Returns false at the end of the stream.
+    fn read(&mut self, out: &mut Vec<f32>) -> anyhow::Result<bool>;
+
+    /// Whether the samples come in real time, rather than as fast as they are read.
+    fn is_live(&self) -> bool {
+        false
+    }
+
+    /// The next thing that happened to the source besides its samples, if any.
+    fn notice(&mut self) -> Option<Notice> {
+        None
+    }
+
+    /// When the last sample read is heard, for sources that know their latency. The events of
+    /// earlier samples are timed back from it.
+    fn time(&self) -> Option<Instant> {
+        None
+    }
+}
+
+/// `time` moved by `seconds`, earlier if negative.
+pub fn shift(time: Instant, seconds: f64) -> Instant {
+    let duration = Duration::from_secs_f64(seconds.abs());
+    if seconds >= 0.0 {
+        time + duration
+    } else {
+        time.checked_sub(duration).unwrap_or(time)
+    }
+}
+
+/// Something that happened to a source besides its samples.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Notice {
+    /// A new track started, with its title.
+    Track(String),
+    /// The source stopped (`true`) or went on (`false`) producing samples.
+    Suspended(bool),
+    /// The stream was moved to another source by the server or the user.
+    Moved { source: String },
+    /// The stream was killed by the server and has been created anew.
+    Recreated,
+    /// The server dropped samples that were not read in time, for the `count`th time.
+    Overflow { count: u64 },
+    /// Another of the mixed sources became the loudest one.
+    Dominant { source: String },
+}
+
+/// Where the samples come from.
+#[derive(Debug, Clone, PartialEq, Default)]
+pub enum Input {
+    /// The PulseAudio sources of the configuration.
+    #[default]
+    Pulse,
+    /// A WAV file.
+    Wav(PathBuf),
+    /// Raw PCM samples on stdin (`-`), laid out as in the PCM spec of the configuration.
+    Stdin,
+    /// A test signal.
+    Generator(generator::Signal),
+    /// An Icecast or Shoutcast stream at an `http://` or `https://` URL.
+    Radio(String),
+    /// The first source of the configuration, captured natively from PipeWire.
+    #[cfg(feature = "pipewire")]
+    PipeWire,
+}
+
+impl Input {
+    /// Whether the input is reopened when it fails or ends, rather than ending the analysis.
+    pub fn reconnects(&self) -> bool {
+        match self {
+            Input::Pulse | Input::Radio(_) => true,
+            #[cfg(feature = "pipewire")]
+            Input::PipeWire => true,
+            _ => false,
+        }
+    }
+}
+
+/// Opens the input of `config`. A source that waits on a server gives up once `stop` is stopped.
+pub fn open(config: &Config, stop: &Stop) -> anyhow::Result<Box<dyn AudioSource>> {
+    Ok(match &config.input {
+        Input::Pulse => {
+            let default = [pulse::Capture::default()];
+            let captures = match config.sources.as_slice() {
+                [] => &default[..],
+                captures => captures,
+            };
+            Box::new(pulse::PulseSource::open(
+                captures,
+                config.mix,
+                config.timing.fragment,
+                stop.clone(),
+            )?)
+        }
+        Input::Wav(path) => Box::new(wav::WavSource::open(path)?),
+        Input::Stdin => Box::new(pcm::PcmSource::new(
+            pcm::PipeReader::new(std::io::stdin())?,
+            config.pcm,
+        )?),
+        Input::Generator(signal) => Box::new(generator::Generator::new(*signal)),
+        Input::Radio(url) => Box::new(radio::RadioSource::open(url)?),
+        #[cfg(feature = "pipewire")]
+        Input::PipeWire => {
+            if config.sources.len() > 1 {
+                eprintln!("only the first source is captured from PipeWire");
+            }
+            let capture = config.sources.first().cloned().unwrap_or_default();
+            Box::new(pipewire::PipeWireSource::open(
+                &capture,
+                config.timing.fragment,
+            )?)
+        }
+    })
+}
@@ -0,0 +1,118 @@
+// SPDX-License-Identifier: EUPL-1.2
+
+use std::{f32::consts::PI, str::FromStr};
+
+use anyhow::{bail, Context};
+use rand::{rngs::SmallRng, Rng, SeedableRng};
+
+use super::{AudioSource, CHUNK};
+
+const SAMPLE_RATE: u32 = 44100;
+const AMPLITUDE: f32 = 0.8;
+const CLICK_FREQUENCY: f32 = 1000.0; // Hz
+const CLICK_DECAY: f32 = 20.0; // Per second
+
+/// A test signal.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Signal {
+    /// A sine wave (`sine:440`).
+    Sine { frequency: f32 },
+    /// Decaying clicks on every beat (`clicks:120`).
+    Clicks { bpm: f32 },
+    /// Noise with equal power per octave (`pink`).
+    PinkNoise,
+}
+
+impl FromStr for Signal {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> anyhow::Result<Self> {
+        let (name, value) = match s.split_once(':') {
+            Some((name, value)) => (name, Some(value)),
+            None => (s, None),
+        };
+        let parse = |default: f32| -> anyhow::Result<f32> {
+            let value = match value {
+                Some(value) => value
+                    .parse()
+                    .with_context(|| format!("invalid {} value {:?}", name, value))?,
+                None => default,
+            };
+            if value <= 0.0 || !value.is_finite() {
+                bail!("{} value must be positive", name);
+            }
+            Ok(value)
+        };
+        match name {
+            "sine" => Ok(Signal::Sine {
+                frequency: parse(440.0)?,
+            }),
+            "clicks" => Ok(Signal::Clicks { bpm: parse(120.0)? }),
+            "pink" if value.is_none() => Ok(Signal::PinkNoise),
+            _ => bail!("unknown signal {:?} (sine[:hz], clicks[:bpm] or pink)", s),
+        }
+    }
+}
+
+/// Generates an endless mono test signal.
+pub struct Generator {
+    signal: Signal,
+    sample_rate: u32,
+    position: u64,
+    rng: SmallRng,
+    pink: [f32; 3],
+}
+
+impl Generator {
+    pub fn new(signal: Signal) -> Generator {
+        Generator::with_sample_rate(signal, SAMPLE_RATE)
+    }
+
+    pub fn with_sample_rate(signal: Signal, sample_rate: u32) -> Generator {
+        Generator {
+            signal,
+            sample_rate,
+            position: 0,
+            rng: SmallRng::seed_from_u64(0),
+            pink: [0.0; 3],
+        }
+    }
+
+    fn next_sample(&mut self) -> f32 {
+        let t = self.position as f64 / self.sample_rate as f64;
+        self.position += 1;
+        match self.signal {
+            Signal::Sine { frequency } => {
+                AMPLITUDE * (2.0 * PI * (t * frequency as f64).fract() as f32).sin()
+            }
+            Signal::Clicks { bpm } => {
+                let t = (t % (60.0 / bpm as f64)) as f32;
+                AMPLITUDE * (2.0 * PI * CLICK_FREQUENCY * t).sin() * (-t * CLICK_DECAY).exp()
+            }
+            // Paul Kellet's economy filter, see https://www.firstpr.com.au/dsp/pink-noise/
+            Signal::PinkNoise => {
+                let white = self.rng.random_range(-1.0..1.0);
+                let [b0, b1, b2] = &mut self.pink;
+                *b0 = 0.99765 * *b0 + white * 0.0990460;
+                *b1 = 0.96300 * *b1 + white * 0.2965164;
+                *b2 = 0.57000 * *b2 + white * 1.0526913;
+                AMPLITUDE * (*b0 + *b1 + *b2 + white * 0.1848) / 8.0
+            }
+        }
+    }
+}
+
+impl AudioSource for Generator {
+    fn sample_rate(&self) -> u32 {
+        self.sample_rate
+    }
+
+    fn channels(&self) -> usize {
+        1
+    }
+
+    fn read(&mut self, out: &mut Vec<f32>) -> anyhow::Result<bool> {
+        out.extend((0..CHUNK).map(|_| self.next_sample()));
+        Ok(true)
+    }
+}
@@ -0,0 +1,197 @@
+// SPDX-License-Identifier: EUPL-1.2
+
+use std::{
+    io::{self, Read},
+    mem,
+    sync::mpsc,
+    thread,
+    time::Duration,
+};
+
+use pulseaudio::protocol::SampleFormat;
+
+use super::{AudioSource, CHUNK};
+use crate::sample;
+
+/// Layout of raw PCM samples, `s16le` at 44.1 kHz in stereo by default.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct PcmSpec {
+    pub format: SampleFormat,
+    pub sample_rate: u32,
+    pub channels: u8,
+}
+
+impl Default for PcmSpec {
+    fn default() -> PcmSpec {
+        PcmSpec {
+            format: SampleFormat::S16Le,
+            sample_rate: 44100,
+            channels: 2,
+        }
+    }
+}
+
+const READ_TIMEOUT: Duration = Duration::from_millis(100); // Longest a read of a pipe waits
+const QUEUE: usize = 16; // Chunks read ahead from a pipe
+const READ_SIZE: usize = 16 * 1024; // Bytes read from a pipe at once
+
+/// Reads raw interleaved PCM samples, as written by `ffmpeg -f s16le -`. A read that times out
+/// returns the whole frames read so far.
+pub struct PcmSource<R> {
+    reader: R,
+    spec: PcmSpec,
+    decoder: sample::Decoder,
+    buf: Vec<u8>,
+}
+
+impl<R: Read> PcmSource<R> {
+    pub fn new(reader: R, spec: PcmSpec) -> anyhow::Result<PcmSource<R>> {
+        Ok(PcmSource {
+            reader,
+            spec,
+            decoder: sample::Decoder::new(spec.format)?,
+            buf: Vec::new(),
+        })
+    }
+}
+
+impl<R: Read + Send> AudioSource for PcmSource<R> {
+    fn sample_rate(&self) -> u32 {
+        self.spec.sample_rate
+    }
+
+    fn channels(&self) -> usize {
+        self.spec.channels as usize
+    }
+
+    fn read(&mut self, out: &mut Vec<f32>) -> anyhow::Result<bool> {
+        let frame_bytes = self.decoder.bytes_per_sample() * self.channels();
+        let size = CHUNK * frame_bytes;
+        let mut end = false;
+        while self.buf.len() < size {
+            let len = self.buf.len();
+            self.buf.resize(size, 0);
+            let read = self.reader.read(&mut self.buf[len..]);
+            self.buf.truncate(len + *read.as_ref().unwrap_or(&0));
+            match read {
+                Ok(0) => {
+                    end = true;
+                    break;
+                }
+                Ok(_) => {}
+                Err(err) if err.kind() == io::ErrorKind::TimedOut => break,
+                Err(err) if err.kind() == io::ErrorKind::Interrupted => {}
+                Err(err) => return Err(err.into()),
+            }
+        }
+        // A partial frame is kept for the next read. It can only be left at the end of the stream.
+        let frames = self.buf.len() - self.buf.len() % frame_bytes;
+        self.decoder.decode(&self.buf[..frames], out);
+        self.buf.drain(..frames);
+        Ok(!end || frames > 0)
+    }
+}
+
+/// Reads a pipe in a thread of its own, so that reads time out with `ErrorKind::TimedOut` after
+/// `READ_TIMEOUT` instead of blocking until the other end writes. The chunks read are handed back
+/// to the thread to be filled again.
+pub struct PipeReader {
+    chunks: mpsc::Receiver<io::Result<Vec<u8>>>,
+    spare: mpsc::Sender<Vec<u8>>,
+    chunk: Vec<u8>,
+    position: usize,
+}
+
+impl PipeReader {
+    pub fn new(mut reader: impl Read + Send + 'static) -> io::Result<PipeReader> {
+        let (tx, chunks) = mpsc::sync_channel(QUEUE);
+        let (spare, spare_rx) = mpsc::channel::<Vec<u8>>();
+        thread::Builder::new()
+            .name("pipe".to_owned())
+            .spawn(move || loop {
+                let mut chunk = spare_rx.try_recv().unwrap_or_default();
+                chunk.resize(READ_SIZE, 0);
+                let read = match reader.read(&mut chunk) {
+                    Err(err) if err.kind() == io::ErrorKind::Interrupted => continue,
+                    read => read,
+                };
+                // Ends after the end of the stream or an error, or once the source is dropped.
+                let more = matches!(read, Ok(len) if len > 0);
+                let read = read.map(|len| {
+                    chunk.truncate(len);
+                    chunk
+                });
+                if tx.send(read).is_err() || !more {
+                    return;
+                }
+            })?;
+        Ok(PipeReader {
+            chunks,
+            spare,
+            chunk: Vec::new(),
+            position: 0,
+        })
+    }
+}
+
+impl Read for PipeReader {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if self.position == self.chunk.len() {
+            let chunk = match self.chunks.recv_timeout(READ_TIMEOUT) {
+                Ok(chunk) => chunk?,
+                Err(mpsc::RecvTimeoutError::Timeout) => return Err(io::ErrorKind::TimedOut.into()),
+                Err(mpsc::RecvTimeoutError::Disconnected) => return Ok(0),
+            };
+            let _ = self.spare.send(mem::replace(&mut self.chunk, chunk));
+            self.position = 0;
+        }
+        let len = buf.len().min(self.chunk.len() - self.position);
+        buf[..len].copy_from_slice(&self.chunk[self.position..self.position + len]);
+        self.position += len;
+        Ok(len)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Gives its bytes in two parts, the second one late.
+    struct Late {
+        parts: Vec<Vec<u8>>,
+    }
+
+    impl Read for Late {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            if self.parts.is_empty() {
+                return Ok(0);
+            }
+            if self.parts.len() == 1 {
+                thread::sleep(READ_TIMEOUT * 3);
+            }
+            let part = self.parts.remove(0);
+            buf[..part.len()].copy_from_slice(&part);
+            Ok(part.len())
+        }
+    }
+
+    #[test]
+    fn pipe_times_out() {
+        let bytes: Vec<u8> = [i16::MIN, 0, 16384, -16384]
+            .iter()
+            .flat_map(|s| s.to_le_bytes())
+            .collect();
+        // The first frame and half of the second one come first.
+        let late = Late {
+            parts: vec![bytes[..6].to_vec(), bytes[6..].to_vec()],
+        };
+        let mut source =
+            PcmSource::new(PipeReader::new(late).unwrap(), PcmSpec::default()).unwrap();
+
+        let mut out = Vec::new();
+        assert!(source.read(&mut out).unwrap());
+        assert_eq!(out, [-1.0, 0.0]);
+        while source.read(&mut out).unwrap() {}
+        assert_eq!(out, [-1.0, 0.0, 0.5, -0.5]);
+    }
+}
@@ -0,0 +1,418 @@
+// SPDX-License-Identifier: EUPL-1.2
+
+use std::{
+    cell::{Cell, RefCell},
+    collections::VecDeque,
+    io::Cursor,
+    rc::Rc,
+    sync::mpsc,
+    thread,
+    time::{Duration, Instant},
+};
+
+use ::pipewire as pw;
+use anyhow::{anyhow, bail, Context};
+use pw::spa;
+
+use super::{
+    pulse::{Capture, Source},
+    shift, AudioSource, Notice,
+};
+
+// The stream converts whatever the node produces to this format.
+const SAMPLE_RATE: u32 = 48000;
+const CHANNELS: u32 = 2;
+const QUEUE: usize = 64; // Buffers between the PipeWire thread and the analyzer
+const READ_TIMEOUT: Duration = Duration::from_millis(100); // Longest a read waits for samples
+
+/// What the PipeWire thread sends to the source.
+enum Message {
+    /// Samples, with the time their last one was captured, from the delay of the graph when
+    /// they were dequeued.
+    Samples {
+        samples: Vec<f32>,
+        time: Instant,
+    },
+    Notice(Notice),
+    /// The thread lost the daemon or failed to capture.
+    Error(anyhow::Error),
+}
+
+/// Captures a node of the PipeWire graph, or the output stream of an application, with the main
+/// loop of PipeWire in a thread of its own. Only the first capture of the configuration is used.
+pub struct PipeWireSource {
+    gain: f32,
+    messages: mpsc::Receiver<Message>,
+    recycle: mpsc::SyncSender<Vec<f32>>,
+    quit: pw::channel::Sender<()>,
+    thread: Option<thread::JoinHandle<()>>,
+    notices: VecDeque<Notice>,
+    time: Option<Instant>,
+}
+
+impl PipeWireSource {
+    /// Captures `capture`, with `fragment` seconds of latency asked to the graph. Nodes that do
+    /// not exist yet are waited for, like applications.
+    pub fn open(capture: &Capture, fragment: f32) -> anyhow::Result<PipeWireSource> {
+        pw::init();
+        let (tx, messages) = mpsc::sync_channel(QUEUE);
+        let (recycle, recycled) = mpsc::sync_channel(QUEUE);
+        let (quit, quit_rx) = pw::channel::channel();
+        let source = capture.source.clone();
+        let thread = thread::Builder::new()
+            .name("pipewire".to_owned())
+            .spawn(move || {
+                if let Err(err) = run(source, fragment, tx.clone(), recycled, quit_rx) {
+                    let _ = tx.send(Message::Error(err));
+                }
+            })?;
+        Ok(PipeWireSource {
+            gain: capture.gain,
+            messages,
+            recycle,
+            quit,
+            thread: Some(thread),
+            notices: VecDeque::new(),
+            time: None,
+        })
+    }
+}
+
+impl AudioSource for PipeWireSource {
+    fn sample_rate(&self) -> u32 {
+        SAMPLE_RATE
+    }
+
+    fn channels(&self) -> usize {
+        CHANNELS as usize
+    }
+
+    fn is_live(&self) -> bool {
+        true
+    }
+
+    // Returns without samples after `READ_TIMEOUT`, for the caller to check for a shutdown.
+    fn read(&mut self, out: &mut Vec<f32>) -> anyhow::Result<bool> {
+        while self.notices.is_empty() {
+            match self.messages.recv_timeout(READ_TIMEOUT) {
+                Ok(Message::Samples { samples, time }) => {
+                    self.time = Some(time);
+                    out.extend(samples.iter().map(|sample| sample * self.gain));
+                    let _ = self.recycle.try_send(samples);
+                    return Ok(true);
+                }
+                Ok(Message::Notice(notice)) => self.notices.push_back(notice),
+                Ok(Message::Error(err)) => return Err(err),
+                Err(mpsc::RecvTimeoutError::Timeout) => return Ok(true),
+                Err(mpsc::RecvTimeoutError::Disconnected) => bail!("PipeWire thread ended"),
+            }
+        }
+        Ok(true)
+    }
+
+    fn notice(&mut self) -> Option<Notice> {
+        self.notices.pop_front()
+    }
+
+    fn time(&self) -> Option<Instant> {
+        self.time
+    }
+}
+
+impl Drop for PipeWireSource {
+    fn drop(&mut self) {
+        let _ = self.quit.send(());
+        if let Some(thread) = self.thread.take() {
+            let _ = thread.join();
+        }
+    }
+}
+
+/// The stream of the PipeWire thread, created anew for each node it follows.
+struct Graph {
+    core: pw::core::Core,
+    tx: mpsc::SyncSender<Message>,
+    recycled: Rc<mpsc::Receiver<Vec<f32>>>,
+    latency: String,
+    // The listener goes first, as it has to be removed before the stream is destroyed.
+    stream: RefCell<Option<(pw::stream::StreamListener<()>, pw::stream::Stream)>>,
+    target: Cell<Option<u32>>,
+}
+
+impl Graph {
+    /// Captures the node `target`, or the default source or sink if none. The output of a sink is
+    /// captured if `capture_sink`.
+    fn connect(&self, target: Option<&str>, capture_sink: bool) -> anyhow::Result<()> {
+        let mut props = pw::properties::Properties::new();
+        props.insert("media.type", "Audio");
+        props.insert("media.category", "Capture");
+        props.insert("media.role", "Music");
+        props.insert("node.latency", self.latency.as_str());
+        if capture_sink {
+            props.insert("stream.capture.sink", "true");
+        }
+        if let Some(target) = target {
+            props.insert("target.object", target);
+        }
+        let stream = pw::stream::Stream::new(&self.core, "isis", props)?;
+
+        let (tx, recycled) = (self.tx.clone(), self.recycled.clone());
+        let state_tx = self.tx.clone();
+        let listener = stream
+            .add_local_listener_with_user_data(())
+            .state_changed(move |_, _, _, state| {
+                let message = match state {
+                    pw::stream::StreamState::Streaming => Message::Notice(Notice::Suspended(false)),
+                    pw::stream::StreamState::Paused => Message::Notice(Notice::Suspended(true)),
+                    pw::stream::StreamState::Error(err) => {
+                        Message::Error(anyhow!("PipeWire stream failed: {}", err))
+                    }
+                    _ => return,
+                };
+                let _ = state_tx.try_send(message);
+            })
+            .process(move |stream, _| {
+                let Some(mut buffer) = stream.dequeue_buffer() else {
+                    return;
+                };
+                let time = shift(Instant::now(), -delay(stream));
+                let datas = buffer.datas_mut();
+                let Some(data) = datas.first_mut() else {
+                    return;
+                };
+                let size = data.chunk().size() as usize;
+                let Some(bytes) = data.data() else {
+                    return;
+                };
+                let mut samples = recycled.try_recv().unwrap_or_default();
+                samples.clear();
+                samples.extend(
+                    bytes[..size.min(bytes.len())]
+                        .chunks_exact(4)
+                        .map(|b| f32::from_le_bytes(b.try_into().unwrap())),
+                );
+                // Samples are dropped rather than queued when the analyzer lags.
+                let _ = tx.try_send(Message::Samples { samples, time });
+            })
+            .register()?;
+
+        let mut info = spa::param::audio::AudioInfoRaw::new();
+        info.set_format(spa::param::audio::AudioFormat::F32LE);
+        info.set_rate(SAMPLE_RATE);
+        info.set_channels(CHANNELS);
+        let mut position = [0; spa::param::audio::MAX_CHANNELS];
+        position[0] = spa::sys::SPA_AUDIO_CHANNEL_FL;
+        position[1] = spa::sys::SPA_AUDIO_CHANNEL_FR;
+        info.set_position(position);
+        let format = spa::pod::serialize::PodSerializer::serialize(
+            Cursor::new(Vec::new()),
+            &spa::pod::Value::Object(spa::pod::Object {
+                type_: spa::utils::SpaTypes::ObjectParamFormat.as_raw(),
+                id: spa::param::ParamType::EnumFormat.as_raw(),
+                properties: info.into(),
+            }),
+        )
+        .context("failed to serialize the format")?
+        .0
+        .into_inner();
+        let mut params = [spa::pod::Pod::from_bytes(&format).context("invalid format")?];
+
+        stream.connect(
+            spa::utils::Direction::Input,
+            None,
+            pw::stream::StreamFlags::AUTOCONNECT | pw::stream::StreamFlags::MAP_BUFFERS,
+            &mut params,
+        )?;
+        *self.stream.borrow_mut() = Some((listener, stream));
+        Ok(())
+    }
+
+    fn disconnect(&self) {
+        self.stream.borrow_mut().take();
+        self.target.set(None);
+    }
+
+    fn notice(&self, notice: Notice) {
+        let _ = self.tx.try_send(Message::Notice(notice));
+    }
+}
+
+/// Seconds since the samples of the stream were captured, as reported by the graph, including
+/// the samples buffered by the stream.
+fn delay(stream: &pw::stream::StreamRef) -> f64 {
+    let mut time: pw::sys::pw_time = unsafe { std::mem::zeroed() };
+    let res = unsafe {
+        pw::sys::pw_stream_get_time_n(
+            stream.as_raw_ptr(),
+            &mut time,
+            std::mem::size_of::<pw::sys::pw_time>(),
+        )
+    };
+    if res < 0 || time.rate.denom == 0 {
+        return 0.0;
+    }
+    time.delay as f64 * time.rate.num as f64 / time.rate.denom as f64
+        + time.buffered as f64 / SAMPLE_RATE as f64
+}
+
+/// Whether the node of `props` is the one to capture, and then whether it is a sink.
+fn matches(source: &Source, props: &spa::utils::dict::DictRef) -> Option<bool> {
+    let class = props.get("media.class")?;
+    let lower = |key: &str| props.get(key).map(str::to_lowercase);
+    let device = class.starts_with("Audio/Source") || class.starts_with("Audio/Sink");
+    let found = match source {
+        Source::Name(name) => device && props.get("node.name") == Some(name.as_str()),
+        Source::Description(description) => {
+            device && lower("node.description").is_some_and(|d| d.contains(description.as_str()))
+        }
+        Source::Application(app) => {
+            class == "Stream/Output/Audio"
+                && (lower("application.process.binary").as_deref() == Some(app.as_str())
+                    || lower("application.name").is_some_and(|name| name.contains(app.as_str())))
+        }
+        Source::Default | Source::DefaultMonitor => false,
+    };
+    found.then(|| class.starts_with("Audio/Sink"))
+}
+
+/// Runs the main loop of PipeWire until `quit` or an error, capturing the first node matching
+/// `source` and the next one when it goes away.
+fn run(
+    source: Source,
+    fragment: f32,
+    tx: mpsc::SyncSender<Message>,
+    recycled: mpsc::Receiver<Vec<f32>>,
+    quit: pw::channel::Receiver<()>,
+) -> anyhow::Result<()> {
+    let mainloop = pw::main_loop::MainLoop::new(None)?;
+    let context = pw::context::Context::new(&mainloop)?;
+    let core = context.connect(None).context("PipeWire not available")?;
+    let registry = core.get_registry()?;
+    let failed: Rc<RefCell<Option<anyhow::Error>>> = Rc::default();
+
+    let _quit = quit.attach(mainloop.loop_(), {
+        let mainloop = mainloop.clone();
+        move |()| mainloop.quit()
+    });
+    let _core_listener = core
+        .add_listener_local()
+        .error({
+            let (mainloop, failed) = (mainloop.clone(), failed.clone());
+            move |id, _, _, message| {
+                if id == pw::core::PW_ID_CORE {
+                    *failed.borrow_mut() = Some(anyhow!("PipeWire failed: {}", message));
+                    mainloop.quit();
+                }
+            }
+        })
+        .register();
+
+    let frames = (fragment * SAMPLE_RATE as f32).max(1.0) as u32;
+    let graph = Rc::new(Graph {
+        core: core.clone(),
+        tx,
+        recycled: Rc::new(recycled),
+        latency: format!("{}/{}", frames, SAMPLE_RATE),
+        stream: RefCell::new(None),
+        target: Cell::new(None),
+    });
+    match &source {
+        Source::Default => graph.connect(None, false)?,
+        Source::DefaultMonitor => graph.connect(None, true)?,
+        source => eprintln!("waiting for {:?}...", source),
+    }
+
+    let _registry_listener = registry
+        .add_listener_local()
+        .global({
+            let (graph, mainloop, failed) = (graph.clone(), mainloop.clone(), failed.clone());
+            move |global| {
+                if graph.target.get().is_some() || global.type_ != pw::types::ObjectType::Node {
+                    return;
+                }
+                let Some(props) = global.props else {
+                    return;
+                };
+                let Some(capture_sink) = matches(&source, props) else {
+                    return;
+                };
+                let target = props
+                    .get("object.serial")
+                    .or_else(|| props.get("node.name"));
+                if let Err(err) = graph.connect(target, capture_sink) {
+                    *failed.borrow_mut() = Some(err);
+                    mainloop.quit();
+                    return;
+                }
+                graph.target.set(Some(global.id));
+                let name = props.get("node.name").unwrap_or_default();
+                graph.notice(Notice::Moved {
+                    source: format!("{} (node {})", name, global.id),
+                });
+            }
+        })
+        .global_remove({
+            let graph = graph.clone();
+            move |id| {
+                if graph.target.get() == Some(id) {
+                    graph.disconnect();
+                    graph.notice(Notice::Suspended(true));
+                }
+            }
+        })
+        .register();
+
+    mainloop.run();
+    graph.disconnect();
+    match failed.take() {
+        Some(err) => Err(err),
+        None => Ok(()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // These need a PipeWire daemon with a null sink named `isis-test`, for example a headless one:
+    //
+    //   pipewire & wireplumber &
+    //   pw-cli create-node adapter '{ factory.name=support.null-audio-sink node.name=isis-test
+    //       media.class=Audio/Sink object.linger=true audio.position=[FL FR] }'
+    //   cargo test --features pipewire -- --ignored
+
+    fn read_for(source: &mut PipeWireSource, time: Duration) -> Vec<f32> {
+        let mut samples = Vec::new();
+        let start = Instant::now();
+        while start.elapsed() < time {
+            source.read(&mut samples).unwrap();
+        }
+        samples
+    }
+
+    #[test]
+    #[ignore]
+    fn captures_null_sink() {
+        let capture = Capture {
+            source: Source::Name("isis-test".to_owned()),
+            gain: 1.0,
+        };
+        let mut source = PipeWireSource::open(&capture, 0.02).unwrap();
+        let samples = read_for(&mut source, Duration::from_secs(2));
+        assert!(samples.len() >= SAMPLE_RATE as usize * CHANNELS as usize);
+        assert!(samples.iter().all(|sample| sample.abs() < 1e-6));
+        let time = source.time().unwrap();
+        assert!(Instant::now().duration_since(time) < Duration::from_secs(1));
+    }
+
+    #[test]
+    #[ignore]
+    fn waits_for_application() {
+        let capture = Capture {
+            source: Source::Application("isis-no-such-app".to_owned()),
+            gain: 1.0,
+        };
+        let mut source = PipeWireSource::open(&capture, 0.02).unwrap();
+        assert!(read_for(&mut source, Duration::from_secs(1)).is_empty());
+    }
+}
@@ -0,0 +1,1194 @@
+// SPDX-License-Identifier: EUPL-1.2
+
+use std::{
+    collections::VecDeque,
+    ffi::{CStr, CString},
+    io::{self, BufReader, Write},
+    net::Shutdown,
+    os::{
+        fd::{AsRawFd, OwnedFd},
+        unix::net::UnixStream,
+    },
+    str::FromStr,
+    sync::mpsc,
+    thread,
+    time::{Duration, Instant, SystemTime},
+};
+
+use anyhow::{bail, Context};
+use mio::{Events, Interest, Poll, Token};
+use pulseaudio::protocol;
+
+use super::{shift, AudioSource, Notice};
+use crate::audio_analyzer::Stop;
+use crate::sample;
+
+mod shm;
+
+/// The PulseAudio source to record from.
+#[derive(Debug, Clone, PartialEq, Default)]
+pub enum Source {
+    /// A source selected by its exact name.
+    Name(String),
+    /// The default source of the server (`@DEFAULT_SOURCE@`).
+    Default,
+    /// The monitor of the default sink of the server (`@DEFAULT_MONITOR@`).
+    #[default]
+    DefaultMonitor,
+    /// The first source whose description contains the text (`~text`), ignoring case.
+    Description(String),
+    /// The playing sink input of an application, matched by its binary or name, ignoring case.
+    Application(String),
+}
+
+impl FromStr for Source {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> anyhow::Result<Self> {
+        match s {
+            "" => bail!("empty source name"),
+            "@DEFAULT_SOURCE@" => Ok(Source::Default),
+            "@DEFAULT_MONITOR@" => Ok(Source::DefaultMonitor),
+            _ => match s.strip_prefix('~') {
+                Some("") => bail!("empty source description"),
+                Some(description) => Ok(Source::Description(description.to_lowercase())),
+                None => Ok(Source::Name(s.to_owned())),
+            },
+        }
+    }
+}
+
+impl Source {
+    fn matches(&self, info: &protocol::SourceInfo, server_info: &protocol::ServerInfo) -> bool {
+        match self {
+            Source::Name(name) => info.name.as_bytes() == name.as_bytes(),
+            Source::Default => Some(&info.name) == server_info.default_source_name.as_ref(),
+            Source::DefaultMonitor => {
+                info.monitor_of_sink_name.is_some()
+                    && info.monitor_of_sink_name == server_info.default_sink_name
+            }
+            Source::Description(description) => info
+                .description
+                .as_deref()
+                .map(CStr::to_string_lossy)
+                .is_some_and(|d| d.to_lowercase().contains(description.as_str())),
+            Source::Application(_) => false,
+        }
+    }
+}
+
+/// Lists the sources known by the server, along with the server defaults.
+pub fn sources() -> anyhow::Result<(protocol::ServerInfo, protocol::SourceInfoList)> {
+    let (mut sock, protocol_version) = connect_and_init().context("failed to initialize client")?;
+    query_sources(&mut sock, protocol_version)
+}
+
+fn query_sources(
+    sock: &mut BufReader<UnixStream>,
+    protocol_version: u16,
+) -> anyhow::Result<(protocol::ServerInfo, protocol::SourceInfoList)> {
+    protocol::write_command_message(
+        sock.get_mut(),
+        2,
+        protocol::Command::GetServerInfo,
+        protocol_version,
+    )?;
+    let (_, server_info) =
+        protocol::read_reply_message::<protocol::ServerInfo>(sock, protocol_version)?;
+
+    protocol::write_command_message(
+        sock.get_mut(),
+        3,
+        protocol::Command::GetSourceInfoList,
+        protocol_version,
+    )?;
+    let (_, source_infos) =
+        protocol::read_reply_message::<protocol::SourceInfoList>(sock, protocol_version)?;
+
+    Ok((server_info, source_infos))
+}
+
+/// Whether the sink input belongs to the application, by binary or by name.
+fn plays(info: &protocol::SinkInputInfo, app: &str) -> bool {
+    let prop = |prop| {
+        info.props
+            .get(prop)
+            .and_then(|value| CStr::from_bytes_until_nul(value).ok())
+            .map(|value| value.to_string_lossy().to_lowercase())
+    };
+    prop(protocol::Prop::ApplicationProcessBinary).is_some_and(|binary| binary == app)
+        || prop(protocol::Prop::ApplicationName).is_some_and(|name| name.contains(app))
+}
+
+/// A PulseAudio source to record from, with the gain applied to it before mixing.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Capture {
+    pub source: Source,
+    pub gain: f32,
+}
+
+impl Default for Capture {
+    fn default() -> Self {
+        Capture {
+            source: Source::default(),
+            gain: 1.0,
+        }
+    }
+}
+
+/// How the streams of several sources are merged into the analyzed signal.
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub enum Mix {
+    /// Sum of all streams.
+    #[default]
+    Sum,
+    /// Only the dominant stream: whoever is playing wins.
+    Loudest,
+}
+
+impl FromStr for Mix {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> anyhow::Result<Self> {
+        match s {
+            "sum" => Ok(Mix::Sum),
+            "loudest" => Ok(Mix::Loudest),
+            _ => bail!("unknown mix {:?} (sum or loudest)", s),
+        }
+    }
+}
+
+const MAX_BUFFER: f32 = 2.0; // Seconds buffered by the server before it drops samples
+const LEVEL_TIME: f32 = 0.5; // Seconds over which the level of a stream is smoothed
+const DOMINANCE: f32 = 2.0; // Level ratio for another stream to become dominant
+const MAX_SKEW: f32 = 0.5; // Seconds a stream may lag before it is padded with silence
+const LATENCY_INTERVAL: Duration = Duration::from_secs(1); // Between two latency measurements
+const POLL_TIMEOUT: Duration = Duration::from_millis(100); // Longest a read waits for the server
+const REPLY_TIMEOUT: Duration = Duration::from_secs(5); // Longest a command waits for the server
+const READ_SIZE: usize = 64 * 1024; // Bytes read from the socket at once
+const SOCKET: Token = Token(0);
+
+// Commands about the transport, which `protocol::Command` does not read.
+const ENABLE_SRBCHANNEL: u32 = protocol::CommandTag::EnableSrbchannel as u32;
+const DISABLE_SRBCHANNEL: u32 = protocol::CommandTag::DisableSrbchannel as u32;
+const REGISTER_MEMFD_SHMID: u32 = protocol::CommandTag::RegisterMemfdShmid as u32;
+
+/// The format of applications, which may change from one sink input to the next.
+const APP_SPEC: protocol::SampleSpec = protocol::SampleSpec {
+    format: protocol::SampleFormat::Float32Le,
+    channels: 2,
+    sample_rate: 44100,
+};
+
+/// Decodes data into the samples of the stream on `channel`. Data still in flight from a killed or
+/// deleted stream is dropped.
+fn decode_into(streams: &mut [Stream], decoder: &sample::Decoder, channel: u32, data: &[u8]) {
+    if let Some(stream) = streams.iter_mut().find(|s| s.channel == channel) {
+        let start = stream.samples.len();
+        decoder.decode(data, &mut stream.samples);
+        for sample in &mut stream.samples[start..] {
+            *sample *= stream.gain;
+        }
+        stream.received += (stream.samples.len() - start) as u64;
+    }
+}
+
+/// The `index`th value of a payload made only of `u32` values, each one tagged with `L`.
+fn tag_u32(payload: &[u8], index: usize) -> Option<u32> {
+    let value = payload.get(5 * index..5 * index + 5)?;
+    (value[0] == b'L').then(|| u32::from_be_bytes(value[1..].try_into().unwrap()))
+}
+
+/// A record stream, on a source or on the sink input of an application.
+struct Stream {
+    label: String,
+    gain: f32,
+    source_index: u32,
+    app: Option<String>,
+    target: Option<u32>,
+    channel: u32,
+    samples: Vec<f32>,
+    /// Samples decoded into `samples` so far, the mixed ones included.
+    received: u64,
+    level: f32,
+    suspended: bool,
+    overflows: u64,
+}
+
+impl Stream {
+    fn new(label: String, gain: f32, source_index: u32, app: Option<String>) -> Stream {
+        Stream {
+            label,
+            gain,
+            source_index,
+            app,
+            target: None,
+            channel: u32::MAX,
+            samples: Vec::new(),
+            received: 0,
+            level: 0.0,
+            suspended: false,
+            overflows: 0,
+        }
+    }
+
+    fn is_open(&self) -> bool {
+        self.channel != u32::MAX
+    }
+}
+
+/// When a sample of a stream is heard, as of the last latency measurement.
+#[derive(Debug, Clone, Copy)]
+struct Anchor {
+    channel: u32,
+    /// Samples received on the stream up to the one heard at `time`.
+    position: u64,
+    time: Instant,
+}
+
+/// What `receive` got from the server.
+enum Received {
+    /// Samples, decoded into their stream.
+    Data,
+    /// Shared memory bookkeeping, handled already.
+    Transport,
+    /// A whole command message, descriptor included.
+    Command(Vec<u8>),
+    /// Nothing before the timeout.
+    Nothing,
+}
+
+/// Records from sources of a PulseAudio server, one record stream per source on a single
+/// connection, and mixes them into one signal. The socket is polled without blocking, and the
+/// bytes received and sent go through buffers kept from one message to the next.
+pub struct PulseSource {
+    sock: mio::net::UnixStream,
+    poll: Poll,
+    events: Events,
+    input: Vec<u8>,
+    output: Vec<u8>,
+    fds: VecDeque<OwnedFd>,
+    segments: shm::Segments,
+    protocol_version: u16,
+    sample_spec: protocol::SampleSpec,
+    channel_map: protocol::ChannelMap,
+    decoder: sample::Decoder,
+    streams: Vec<Stream>,
+    mix: Mix,
+    fragment: f32,
+    dominant: Option<usize>,
+    started: bool,
+    suspended: bool,
+    notices: VecDeque<Notice>,
+    seq: u32,
+    commands: VecDeque<protocol::Command>,
+    dirty: bool,
+    anchor: Option<Anchor>,
+    measured: Option<Instant>,
+    stop: Stop,
+}
+
+impl PulseSource {
+    /// Opens a record stream for each capture. The streams all take the format of the first
+    /// source, or a fixed one if only applications are captured, since the sink input of an
+    /// application may change format from one to the next. The server sends `fragment` seconds
+    /// of samples at once. Waiting for the server ends once `stop` is stopped.
+    pub fn open(
+        captures: &[Capture],
+        mix: Mix,
+        fragment: f32,
+        stop: Stop,
+    ) -> anyhow::Result<PulseSource> {
+        let mut pulse_source =
+            PulseSource::connect(mix, fragment, stop).context("failed to initialize client")?;
+
+        let server_info: protocol::ServerInfo =
+            pulse_source.request(protocol::Command::GetServerInfo)?;
+        let source_infos: protocol::SourceInfoList =
+            pulse_source.request(protocol::Command::GetSourceInfoList)?;
+        let find = |source: &Source| {
+            source_infos
+                .iter()
+                .find(|info| source.matches(info, &server_info))
+                .cloned()
+        };
+
+        let mut spec = None;
+        for capture in captures {
+            if let Source::Application(app) = &capture.source {
+                pulse_source.streams.push(Stream::new(
+                    app.clone(),
+                    capture.gain,
+                    u32::MAX,
+                    Some(app.clone()),
+                ));
+                continue;
+            }
+            let source_info = match find(&capture.source) {
+                Some(info) => info,
+                None if capture.source != Source::DefaultMonitor => {
+                    eprintln!(
+                        "source {:?} not found, falling back to the monitor of the default sink",
+                        capture.source
+                    );
+                    find(&Source::DefaultMonitor)
+                        .context("no monitor found for the default sink")?
+                }
+                None => bail!("no monitor found for the default sink"),
+            };
+            let label = source_info
+                .description
+                .as_ref()
+                .unwrap_or(&source_info.name)
+                .to_string_lossy()
+                .into_owned();
+            eprintln!("recording from source: {:?}...", label);
+            spec.get_or_insert((source_info.sample_spec, source_info.channel_map));
+            pulse_source
+                .streams
+                .push(Stream::new(label, capture.gain, source_info.index, None));
+        }
+
+        if let Some((sample_spec, channel_map)) = spec {
+            pulse_source.sample_spec = sample_spec;
+            pulse_source.channel_map = channel_map;
+            pulse_source.decoder = sample::Decoder::new(sample_spec.format)?;
+        }
+        for index in 0..pulse_source.streams.len() {
+            if pulse_source.streams[index].app.is_none() {
+                pulse_source.create_stream(index)?;
+            }
+        }
+        if pulse_source
+            .streams
+            .iter()
+            .any(|stream| stream.app.is_some())
+        {
+            pulse_source.ack(protocol::Command::Subscribe(
+                protocol::SubscriptionMask::SINK_INPUT,
+            ))?;
+            pulse_source.follow()?;
+            for stream in &pulse_source.streams {
+                if let (Some(app), None) = (&stream.app, stream.target) {
+                    eprintln!("waiting for {} to play...", app);
+                }
+            }
+        }
+        Ok(pulse_source)
+    }
+
+    /// Connects to the server and authenticates, offering to take the samples through memfd or
+    /// POSIX shared memory. The server falls back to sending them through the socket if it can
+    /// not. The format is the fixed one of applications until a source sets it.
+    fn connect(mix: Mix, fragment: f32, stop: Stop) -> anyhow::Result<PulseSource> {
+        let sock = UnixStream::connect(socket_path()?)?;
+        sock.set_nonblocking(true)?;
+        let mut sock = mio::net::UnixStream::from_std(sock);
+        let poll = Poll::new()?;
+        poll.registry()
+            .register(&mut sock, SOCKET, Interest::READABLE | Interest::WRITABLE)?;
+
+        let mut pulse_source = PulseSource {
+            sock,
+            poll,
+            events: Events::with_capacity(8),
+            input: Vec::new(),
+            output: Vec::new(),
+            fds: VecDeque::new(),
+            segments: shm::Segments::default(),
+            protocol_version: protocol::MAX_VERSION,
+            sample_spec: APP_SPEC,
+            channel_map: protocol::ChannelMap::stereo(),
+            decoder: sample::Decoder::new(APP_SPEC.format)?,
+            streams: Vec::new(),
+            mix,
+            fragment,
+            dominant: None,
+            started: false,
+            suspended: false,
+            notices: VecDeque::new(),
+            seq: 0,
+            commands: VecDeque::new(),
+            dirty: false,
+            anchor: None,
+            measured: None,
+            stop,
+        };
+
+        let auth_reply: protocol::AuthReply =
+            pulse_source.request(protocol::Command::Auth(auth_params(true)))?;
+        pulse_source.protocol_version = std::cmp::min(protocol::MAX_VERSION, auth_reply.version);
+        let transport = match (auth_reply.use_memfd, auth_reply.use_shm) {
+            (true, _) => "memfd",
+            (false, true) => "POSIX shared memory",
+            (false, false) => "socket",
+        };
+        eprintln!("samples through: {}", transport);
+
+        let _: protocol::SetClientNameReply =
+            pulse_source.request(protocol::Command::SetClientName(client_props()))?;
+        Ok(pulse_source)
+    }
+
+    /// Reads the next message from the server, waiting at most `timeout` for it. Data is decoded
+    /// into the samples of its stream and data of unknown streams is dropped.
+    fn receive(&mut self, timeout: Option<Duration>) -> anyhow::Result<Received> {
+        loop {
+            if let Some(received) = self.parse()? {
+                return Ok(received);
+            }
+
+            // Read until the socket would block, as the poll only reports new bytes.
+            let len = self.input.len();
+            self.input.resize(len + READ_SIZE, 0);
+            match shm::recv(self.sock.as_raw_fd(), &mut self.input[len..], &mut self.fds) {
+                Ok(0) => bail!("connection closed by the server"),
+                Ok(read) => self.input.truncate(len + read),
+                Err(err) if err.kind() == io::ErrorKind::WouldBlock => {
+                    self.input.truncate(len);
+                    self.poll.poll(&mut self.events, timeout)?;
+                    if self.events.is_empty() {
+                        return Ok(Received::Nothing);
+                    }
+                }
+                Err(err) if err.kind() == io::ErrorKind::Interrupted => self.input.truncate(len),
+                Err(err) => return Err(err.into()),
+            }
+        }
+    }
+
+    /// Takes the first message out of the bytes received, if they hold all of it.
+    fn parse(&mut self) -> anyhow::Result<Option<Received>> {
+        if self.input.len() < protocol::DESCRIPTOR_SIZE {
+            return Ok(None);
+        }
+        let desc = protocol::read_descriptor(&mut &self.input[..])?;
+        let end = protocol::DESCRIPTOR_SIZE + desc.length as usize;
+        if self.input.len() < end {
+            return Ok(None);
+        }
+        // The flags of shared memory are not known to `DescriptorFlags`.
+        let flags = u32::from_be_bytes(self.input[16..20].try_into().unwrap());
+        let payload = &self.input[protocol::DESCRIPTOR_SIZE..end];
+
+        let received = match flags & shm::FLAG_SHMMASK {
+            // Blocks are released as soon as they are read, so revoking them changes nothing. No
+            // blocks are exported to be released.
+            shm::FLAG_SHMRELEASE | shm::FLAG_SHMREVOKE => Received::Transport,
+            // A channel of -1 is a command message. Everything else is data.
+            _ if desc.channel == u32::MAX => match tag_u32(payload, 0) {
+                Some(REGISTER_MEMFD_SHMID) => {
+                    let shm_id = tag_u32(payload, 2).context("invalid memfd registration")?;
+                    let fd = self
+                        .fds
+                        .pop_front()
+                        .context("memfd registration without memfd")?;
+                    self.segments
+                        .register_memfd(shm_id, fd)
+                        .context("failed to map memfd segment")?;
+                    Received::Transport
+                }
+                // The ring buffer channel is not used, only its descriptors are closed. The
+                // server keeps to the socket until the client acknowledges it.
+                Some(ENABLE_SRBCHANNEL) => {
+                    self.fds.drain(..2.min(self.fds.len()));
+                    Received::Transport
+                }
+                Some(DISABLE_SRBCHANNEL) => Received::Transport,
+                _ => Received::Command(self.input[..end].to_vec()),
+            },
+            mask if mask & shm::FLAG_SHMDATA != 0 => {
+                if payload.len() != shm::SHM_INFO_SIZE {
+                    bail!("invalid shared memory block of {} bytes", payload.len());
+                }
+                let info =
+                    |i: usize| u32::from_be_bytes(payload[4 * i..4 * i + 4].try_into().unwrap());
+                let (block_id, shm_id, offset, len) = (info(0), info(1), info(2), info(3));
+                let memfd = flags & shm::FLAG_SHMDATA_MEMFD_BLOCK != 0;
+                let data = self
+                    .segments
+                    .block(memfd, shm_id, offset, len)
+                    .with_context(|| {
+                        format!("failed to read block {} of segment {}", block_id, shm_id)
+                    })?;
+                decode_into(&mut self.streams, &self.decoder, desc.channel, data);
+                self.input.drain(..end);
+                self.release(block_id)?;
+                return Ok(Some(Received::Data));
+            }
+            _ => {
+                decode_into(&mut self.streams, &self.decoder, desc.channel, payload);
+                Received::Data
+            }
+        };
+        self.input.drain(..end);
+        Ok(Some(received))
+    }
+
+    /// Hands a block of shared memory back to the server.
+    fn release(&mut self, block_id: u32) -> anyhow::Result<()> {
+        self.output.clear();
+        protocol::write_descriptor(
+            &mut self.output,
+            protocol::Descriptor {
+                length: 0,
+                channel: u32::MAX,
+                offset: (block_id as u64) << 32,
+                flags: protocol::DescriptorFlags::FLAG_SHMRELEASE,
+            },
+        )?;
+        self.flush()
+    }
+
+    /// Writes a whole command message, waiting for the socket to take it.
+    fn write(&mut self, seq: u32, command: protocol::Command) -> anyhow::Result<()> {
+        self.output.clear();
+        protocol::write_command_message(&mut self.output, seq, command, self.protocol_version)?;
+        self.flush()
+    }
+
+    fn flush(&mut self) -> anyhow::Result<()> {
+        let deadline = Instant::now() + REPLY_TIMEOUT;
+        let mut written = 0;
+        while written < self.output.len() {
+            match self.sock.write(&self.output[written..]) {
+                Ok(0) => bail!("connection closed by the server"),
+                Ok(len) => written += len,
+                Err(err) if err.kind() == io::ErrorKind::WouldBlock => {
+                    self.check(deadline)?;
+                    self.poll.poll(&mut self.events, Some(POLL_TIMEOUT))?;
+                }
+                Err(err) if err.kind() == io::ErrorKind::Interrupted => {}
+                Err(err) => return Err(err.into()),
+            }
+        }
+        Ok(())
+    }
+
+    /// Fails once the server has kept the client waiting past `deadline`, or once stopped.
+    fn check(&self, deadline: Instant) -> anyhow::Result<()> {
+        if self.stop.is_stopped() {
+            bail!("stopped while waiting for the server");
+        }
+        if Instant::now() >= deadline {
+            bail!("the server did not answer within {:?}", REPLY_TIMEOUT);
+        }
+        Ok(())
+    }
+
+    /// Sends a command and waits for its reply, keeping the data and the other commands received
+    /// meanwhile for `read`.
+    fn send(&mut self, command: protocol::Command) -> anyhow::Result<Vec<u8>> {
+        self.seq += 1;
+        self.write(self.seq, command)?;
+        let deadline = Instant::now() + REPLY_TIMEOUT;
+        loop {
+            let Received::Command(msg) = self.receive(Some(POLL_TIMEOUT))? else {
+                self.check(deadline)?;
+                continue;
+            };
+            match protocol::Command::read_tag_prefixed(
+                &mut &msg[protocol::DESCRIPTOR_SIZE..],
+                self.protocol_version,
+            ) {
+                Ok((seq, protocol::Command::Reply)) if seq == self.seq => return Ok(msg),
+                Ok((_, command)) => self.commands.push_back(command),
+                // Errors are not tied to their sequence, but only one command waits at a time.
+                Err(protocol::ProtocolError::ServerError(_)) => return Ok(msg),
+                Err(err) => return Err(err.into()),
+            }
+        }
+    }
+
+    fn request<T: protocol::CommandReply>(
+        &mut self,
+        command: protocol::Command,
+    ) -> anyhow::Result<T> {
+        let msg = self.send(command)?;
+        let (_, reply) = protocol::read_reply_message(&mut msg.as_slice(), self.protocol_version)?;
+        Ok(reply)
+    }
+
+    fn ack(&mut self, command: protocol::Command) -> anyhow::Result<()> {
+        let msg = self.send(command)?;
+        protocol::read_ack_message(&mut msg.as_slice())?;
+        Ok(())
+    }
+
+    /// Picks the sink input to record for each application: the current one while it plays,
+    /// otherwise the newest one playing.
+    fn follow(&mut self) -> anyhow::Result<()> {
+        if self.streams.iter().all(|stream| stream.app.is_none()) {
+            return Ok(());
+        }
+        let sink_inputs: protocol::SinkInputInfoList =
+            self.request(protocol::Command::GetSinkInputInfoList)?;
+        for index in 0..self.streams.len() {
+            let Some(app) = self.streams[index].app.clone() else {
+                continue;
+            };
+            let current = self.streams[index].target;
+            let target = sink_inputs
+                .iter()
+                .filter(|info| !info.corked && plays(info, &app))
+                .map(|info| info.index)
+                .max_by_key(|&sink_input| (Some(sink_input) == current, sink_input));
+            if target == current && self.streams[index].is_open() {
+                continue;
+            }
+
+            if self.streams[index].is_open() {
+                self.ack(protocol::Command::DeleteRecordStream(
+                    self.streams[index].channel,
+                ))?;
+                self.streams[index].channel = u32::MAX;
+            }
+            self.streams[index].target = target;
+            match target {
+                Some(sink_input) => {
+                    self.create_stream(index)?;
+                    self.notices.push_back(Notice::Moved {
+                        source: format!("{} (sink input {})", app, sink_input),
+                    });
+                    self.set_suspended(index, false);
+                }
+                None => self.set_suspended(index, true),
+            }
+        }
+        Ok(())
+    }
+
+    /// Creates the record stream of a source or of a followed sink input on the server. The first
+    /// stream sets the format of all the others.
+    fn create_stream(&mut self, index: usize) -> anyhow::Result<()> {
+        let channels = self.sample_spec.channels;
+        let stream = &self.streams[index];
+        let frame = channels as u32 * self.sample_spec.format.bytes_per_sample() as u32;
+        let bytes =
+            |time: f32| (time * self.sample_spec.sample_rate as f32).max(1.0) as u32 * frame;
+        let record_stream: protocol::CreateRecordStreamReply = self.request(
+            protocol::Command::CreateRecordStream(protocol::RecordStreamParams {
+                source_index: stream.target.is_none().then_some(stream.source_index),
+                direct_on_input_index: stream.target,
+                sample_spec: self.sample_spec,
+                channel_map: self.channel_map,
+                cvolume: Some(protocol::ChannelVolume::norm(channels as usize)),
+                buffer_attr: protocol::stream::BufferAttr {
+                    max_length: bytes(MAX_BUFFER),
+                    fragment_size: bytes(self.fragment),
+                    ..Default::default()
+                },
+                flags: protocol::stream::StreamFlags {
+                    adjust_latency: true,
+                    ..Default::default()
+                },
+                ..Default::default()
+            }),
+        )?;
+        eprintln!("stream: {:#?}", record_stream);
+
+        // The analysis can not follow a change of format once started.
+        if !self.started {
+            self.started = true;
+            self.sample_spec = record_stream.sample_spec;
+            self.decoder = sample::Decoder::new(record_stream.sample_spec.format)?;
+        } else if record_stream.sample_spec != self.sample_spec {
+            bail!(
+                "the stream on {:?} came in {:?}",
+                self.streams[index].label,
+                record_stream.sample_spec
+            );
+        }
+        self.streams[index].channel = record_stream.channel_index;
+        // The samples of a new stream are tied to the clock anew.
+        self.measured = None;
+        Ok(())
+    }
+
+    /// Turns the stream commands of the server into notices. The moved and suspended commands
+    /// name the stream by its channel, like the others.
+    fn handle(&mut self, msg: protocol::Command) -> anyhow::Result<()> {
+        let channel = match &msg {
+            protocol::Command::RecordStreamKilled(channel)
+            | protocol::Command::Overflow(channel) => Some(*channel),
+            protocol::Command::RecordStreamMoved(params) => Some(params.stream_index),
+            protocol::Command::RecordStreamSuspended(params) => Some(params.stream_index),
+            _ => None,
+        };
+        let index = channel.and_then(|channel| {
+            self.streams
+                .iter()
+                .position(|stream| stream.is_open() && stream.channel == channel)
+        });
+
+        match (msg, index) {
+            // The stream on a sink input dies with it, the next one is picked by `follow`.
+            (protocol::Command::RecordStreamKilled(_), Some(index))
+                if self.streams[index].app.is_some() =>
+            {
+                self.streams[index].channel = u32::MAX;
+                self.dirty = true;
+            }
+            (protocol::Command::RecordStreamKilled(_), Some(index)) => {
+                self.create_stream(index)
+                    .context("failed to recreate the killed record stream")?;
+                self.notices.push_back(Notice::Recreated);
+            }
+            (protocol::Command::RecordStreamMoved(params), Some(index)) => {
+                self.streams[index].source_index = params.device_index;
+                self.notices.push_back(Notice::Moved {
+                    source: params.device_name.to_string_lossy().into_owned(),
+                });
+                self.set_suspended(index, params.device_suspended);
+            }
+            (protocol::Command::RecordStreamSuspended(params), Some(index)) => {
+                self.set_suspended(index, params.suspended);
+            }
+            (protocol::Command::Overflow(_), Some(index)) => {
+                let stream = &mut self.streams[index];
+                stream.overflows += 1;
+                self.notices.push_back(Notice::Overflow {
+                    count: stream.overflows,
+                });
+            }
+            (protocol::Command::SubscribeEvent(event), _)
+                if event.event_facility == protocol::SubscriptionEventFacility::SinkInput =>
+            {
+                self.dirty = true;
+            }
+            (msg, _) => eprintln!("received command from server: {:#?}", msg),
+        }
+        Ok(())
+    }
+
+    /// Asks the server for the latency of the first open stream, and ties the position of its
+    /// samples to the clock. The newest sample when the server replies comes after the samples
+    /// received meanwhile and those still queued on the server. It was captured the latency of the
+    /// source before the reply, which takes half the round trip, and a monitor plays it after the
+    /// latency of its sink.
+    fn measure_latency(&mut self) -> anyhow::Result<()> {
+        let Some(channel) = self.streams.iter().find(|s| s.is_open()).map(|s| s.channel) else {
+            return Ok(());
+        };
+        let sent = Instant::now();
+        self.measured = Some(sent);
+        let latency: protocol::RecordLatency = self.request(
+            protocol::Command::GetRecordLatency(protocol::LatencyParams {
+                channel,
+                now: SystemTime::now(),
+            }),
+        )?;
+        let replied = sent + sent.elapsed() / 2;
+        let Some(stream) = self.streams.iter().find(|s| s.channel == channel) else {
+            return Ok(());
+        };
+        let queued = (latency.write_offset - latency.read_offset).max(0) as u64
+            / self.sample_spec.format.bytes_per_sample() as u64;
+        let delay = latency.sink_usec as f64 / 1e6 - latency.source_usec as f64 / 1e6;
+        self.anchor = Some(Anchor {
+            channel,
+            position: stream.received + queued,
+            time: shift(replied, delay),
+        });
+        Ok(())
+    }
+
+    /// The mix is suspended when all of its streams are.
+    fn set_suspended(&mut self, index: usize, suspended: bool) {
+        self.streams[index].suspended = suspended;
+        let suspended = self.streams.iter().all(|stream| stream.suspended);
+        if suspended != self.suspended {
+            self.suspended = suspended;
+            self.notices.push_back(Notice::Suspended(suspended));
+        }
+    }
+
+    /// Mixes the samples that all the playing streams have into `out`. Returns false if there is
+    /// nothing to mix yet.
+    fn mix_into(&mut self, out: &mut Vec<f32>) -> bool {
+        let channels = self.sample_spec.channels as usize;
+        let most = self.streams.iter().map(|s| s.samples.len()).max();
+        let most = most.unwrap_or(0);
+        let ready = self
+            .streams
+            .iter()
+            .filter(|s| s.is_open() && !s.suspended)
+            .map(|s| s.samples.len())
+            .min()
+            .unwrap_or(most);
+        // A stream lagging too far behind is padded with silence rather than stalling the others.
+        let skew = (MAX_SKEW * self.sample_spec.sample_rate as f32) as usize * channels;
+        let len = if most > skew { most } else { ready };
+        let len = len - len % channels;
+        if len == 0 {
+            return false;
+        }
+
+        let sample = |stream: &Stream, i: usize| stream.samples.get(i).copied().unwrap_or(0.0);
+        self.update_dominant(len);
+        match (self.mix, self.dominant) {
+            (Mix::Loudest, Some(dominant)) => {
+                let stream = &self.streams[dominant];
+                out.extend((0..len).map(|i| sample(stream, i)));
+            }
+            (Mix::Loudest, None) => out.extend(std::iter::repeat_n(0.0, len)),
+            (Mix::Sum, _) => {
+                out.extend((0..len).map(|i| self.streams.iter().map(|s| sample(s, i)).sum::<f32>()))
+            }
+        }
+        for stream in &mut self.streams {
+            stream.samples.drain(..len.min(stream.samples.len()));
+        }
+        true
+    }
+
+    /// Tracks the levels of the next `len` samples of the streams, and reports the loudest one
+    /// when it gets clearly louder than the previous one.
+    fn update_dominant(&mut self, len: usize) {
+        let frames = (len / self.sample_spec.channels as usize) as f32;
+        let weight = (frames / (LEVEL_TIME * self.sample_spec.sample_rate as f32)).min(1.0);
+        for stream in &mut self.streams {
+            let samples = &stream.samples[..len.min(stream.samples.len())];
+            let rms = (samples.iter().map(|s| s * s).sum::<f32>() / len as f32).sqrt();
+            stream.level += (rms - stream.level) * weight;
+        }
+
+        let loudest = (0..self.streams.len())
+            .max_by(|&a, &b| self.streams[a].level.total_cmp(&self.streams[b].level))
+            .filter(|&index| self.streams[index].level > 0.0);
+        let dominant = match (self.dominant, loudest) {
+            (Some(dominant), Some(loudest))
+                if self.streams[loudest].level <= self.streams[dominant].level * DOMINANCE =>
+            {
+                Some(dominant)
+            }
+            (dominant, None) => dominant,
+            (_, loudest) => loudest,
+        };
+        if dominant != self.dominant {
+            self.dominant = dominant;
+            if let (Some(index), true) = (dominant, self.streams.len() > 1) {
+                self.notices.push_back(Notice::Dominant {
+                    source: self.streams[index].label.clone(),
+                });
+            }
+        }
+    }
+}
+
+impl AudioSource for PulseSource {
+    fn sample_rate(&self) -> u32 {
+        self.sample_spec.sample_rate
+    }
+
+    fn channels(&self) -> usize {
+        self.sample_spec.channels as usize
+    }
+
+    fn is_live(&self) -> bool {
+        true
+    }
+
+    // Reads messages from the server until the next mixed samples or notice, or until the poll
+    // times out, so that the caller gets to check for a shutdown every `POLL_TIMEOUT`.
+    fn read(&mut self, out: &mut Vec<f32>) -> anyhow::Result<bool> {
+        loop {
+            if let Some(msg) = self.commands.pop_front() {
+                self.handle(msg)?;
+                continue;
+            }
+            if self.dirty {
+                self.dirty = false;
+                self.follow()?;
+            }
+            if self
+                .measured
+                .is_none_or(|measured| measured.elapsed() >= LATENCY_INTERVAL)
+            {
+                if let Err(err) = self.measure_latency() {
+                    eprintln!("failed to measure the latency: {:#}", err);
+                }
+            }
+            if self.mix_into(out) || !self.notices.is_empty() {
+                return Ok(true);
+            }
+
+            match self.receive(Some(POLL_TIMEOUT))? {
+                Received::Data | Received::Transport => {}
+                Received::Command(msg) => {
+                    let (_, msg) = protocol::Command::read_tag_prefixed(
+                        &mut &msg[protocol::DESCRIPTOR_SIZE..],
+                        self.protocol_version,
+                    )?;
+                    self.commands.push_back(msg);
+                }
+                Received::Nothing => return Ok(true),
+            }
+        }
+    }
+
+    fn notice(&mut self) -> Option<Notice> {
+        self.notices.pop_front()
+    }
+
+    // Counted from the last latency measurement, by the samples mixed since on its stream.
+    fn time(&self) -> Option<Instant> {
+        let anchor = self.anchor?;
+        let stream = self
+            .streams
+            .iter()
+            .find(|s| s.is_open() && s.channel == anchor.channel)?;
+        let mixed = stream.received - stream.samples.len() as u64;
+        let rate = self.sample_spec.sample_rate as f64 * self.sample_spec.channels as f64;
+        Some(shift(
+            anchor.time,
+            (mixed as f64 - anchor.position as f64) / rate,
+        ))
+    }
+}
+
+const PLAYBACK_LATENCY: f32 = 0.25; // Seconds buffered by the server before playing
+const PLAYBACK_SEQ: u32 = 98; // Sequence of the first command on the playback stream
+
+/// Plays `f32` samples on the default sink of the PulseAudio server.
+pub struct Playback {
+    sock: UnixStream,
+    channel: u32,
+    protocol_version: u16,
+    byte_rate: f64,
+    buf: Vec<u8>,
+    written: u64,
+    seq: u32,
+    requested: Option<(u32, Instant)>,
+    measured: Option<Instant>,
+    latencies: mpsc::Receiver<(u32, protocol::PlaybackLatency, Instant)>,
+    anchor: Option<(u64, Instant)>,
+}
+
+impl Playback {
+    pub fn open(sample_rate: u32, channels: u8) -> anyhow::Result<Playback> {
+        let (mut sock, protocol_version) =
+            connect_and_init().context("failed to initialize client")?;
+
+        let channel_map = match channels {
+            1 => protocol::ChannelMap::mono(),
+            2 => protocol::ChannelMap::stereo(),
+            _ => bail!("unsupported channel count: {}", channels),
+        };
+        let frame_bytes =
+            channels as u32 * protocol::SampleFormat::Float32Le.bytes_per_sample() as u32;
+
+        // Create the playback stream on the server.
+        protocol::write_command_message(
+            sock.get_mut(),
+            PLAYBACK_SEQ,
+            protocol::Command::CreatePlaybackStream(protocol::PlaybackStreamParams {
+                sample_spec: protocol::SampleSpec {
+                    format: protocol::SampleFormat::Float32Le,
+                    channels,
+                    sample_rate,
+                },
+                channel_map,
+                cvolume: Some(protocol::ChannelVolume::norm(channels as usize)),
+                sink_name: Some(CString::new("@DEFAULT_SINK@")?),
+                buffer_attr: protocol::stream::BufferAttr {
+                    target_length: (PLAYBACK_LATENCY * sample_rate as f32) as u32 * frame_bytes,
+                    ..Default::default()
+                },
+                ..Default::default()
+            }),
+            protocol_version,
+        )?;
+
+        let (_, playback_stream) = protocol::read_reply_message::<
+            protocol::CreatePlaybackStreamReply,
+        >(&mut sock, protocol_version)?;
+        eprintln!("playback stream: {:#?}", playback_stream);
+
+        // Samples are written as they are analyzed rather than when the server requests them, but
+        // the server messages still have to be read for the socket not to fill up. The replies
+        // are those to the latency requests, passed on with the time they came.
+        let writer = sock.get_ref().try_clone()?;
+        let (latency_tx, latencies) = mpsc::channel();
+        thread::spawn(move || loop {
+            let msg = match read_message(&mut sock) {
+                Ok(msg) => msg,
+                Err(err) => {
+                    eprintln!("playback stream closed: {}", err);
+                    break;
+                }
+            };
+            let received = Instant::now();
+            match protocol::Command::read_tag_prefixed(
+                &mut &msg[protocol::DESCRIPTOR_SIZE..],
+                protocol_version,
+            ) {
+                Ok((_, protocol::Command::Reply)) => {
+                    match protocol::read_reply_message(&mut msg.as_slice(), protocol_version) {
+                        Ok((seq, latency)) => {
+                            if latency_tx.send((seq, latency, received)).is_err() {
+                                break;
+                            }
+                        }
+                        Err(err) => eprintln!("invalid playback latency: {}", err),
+                    }
+                }
+                Ok((_, protocol::Command::Request(_) | protocol::Command::Started(_))) => {}
+                Ok((_, msg)) => eprintln!("received command from server: {:#?}", msg),
+                Err(err) => eprintln!("received error from server: {}", err),
+            }
+        });
+
+        Ok(Playback {
+            sock: writer,
+            channel: playback_stream.channel,
+            protocol_version,
+            byte_rate: (sample_rate * frame_bytes) as f64,
+            buf: Vec::new(),
+            written: 0,
+            seq: PLAYBACK_SEQ,
+            requested: None,
+            measured: None,
+            latencies,
+            anchor: None,
+        })
+    }
+
+    /// When the last sample written will be heard. Until the server reports its latency, the
+    /// samples are taken to be buffered for the target latency of the stream.
+    pub fn time(&self) -> Instant {
+        let now = Instant::now();
+        let Some((position, time)) = self.anchor else {
+            return now + Duration::from_secs_f32(PLAYBACK_LATENCY);
+        };
+        let queued = (self.written as f64 - position as f64) / self.byte_rate;
+        // After an underrun the server plays the next samples as soon as they come.
+        shift(time, queued).max(now)
+    }
+
+    /// Queues the interleaved `samples` for playback.
+    pub fn write(&mut self, samples: &[f32]) -> anyhow::Result<()> {
+        self.buf.clear();
+        self.buf
+            .extend(samples.iter().flat_map(|sample| sample.to_le_bytes()));
+        protocol::write_memblock(&mut self.sock, self.channel, &self.buf, 0)?;
+        self.written += self.buf.len() as u64;
+
+        while let Ok((seq, latency, received)) = self.latencies.try_recv() {
+            self.anchor_latency(seq, latency, received);
+        }
+        if self
+            .measured
+            .is_none_or(|measured| measured.elapsed() >= LATENCY_INTERVAL)
+        {
+            self.measure_latency()?;
+        }
+        Ok(())
+    }
+
+    /// Asks the server for the latency of the stream. The reply is read by the thread reading the
+    /// server messages, and a reply that is not to the last request is dropped.
+    fn measure_latency(&mut self) -> anyhow::Result<()> {
+        self.seq += 1;
+        let sent = Instant::now();
+        self.requested = Some((self.seq, sent));
+        self.measured = Some(sent);
+        protocol::write_command_message(
+            &mut self.sock,
+            self.seq,
+            protocol::Command::GetPlaybackLatency(protocol::LatencyParams {
+                channel: self.channel,
+                now: SystemTime::now(),
+            }),
+            self.protocol_version,
+        )?;
+        Ok(())
+    }
+
+    /// Ties the position of the samples to the clock. The sample at the read offset of the server
+    /// when it replies, which takes half the round trip, is heard after the latency of the sink.
+    fn anchor_latency(&mut self, seq: u32, latency: protocol::PlaybackLatency, received: Instant) {
+        let Some((requested, sent)) = self.requested else {
+            return;
+        };
+        if seq != requested {
+            return;
+        }
+        self.requested = None;
+        let replied = sent + received.saturating_duration_since(sent) / 2;
+        self.anchor = Some((
+            latency.read_offset.max(0) as u64,
+            shift(replied, latency.sink_usec as f64 / 1e6),
+        ));
+    }
+}
+
+/// Reads a whole message, with its descriptor.
+fn read_message(sock: &mut impl io::Read) -> io::Result<Vec<u8>> {
+    let mut msg = vec![0; protocol::DESCRIPTOR_SIZE];
+    sock.read_exact(&mut msg)?;
+    let desc = protocol::read_descriptor(&mut msg.as_slice())
+        .map_err(|err| io::Error::new(io::ErrorKind::InvalidData, err))?;
+    msg.resize(protocol::DESCRIPTOR_SIZE + desc.length as usize, 0);
+    sock.read_exact(&mut msg[protocol::DESCRIPTOR_SIZE..])?;
+    Ok(msg)
+}
+
+impl Drop for Playback {
+    // Also ends the thread reading the server messages.
+    fn drop(&mut self) {
+        let _ = self.sock.shutdown(Shutdown::Both);
+    }
+}
+
+fn socket_path() -> anyhow::Result<std::path::PathBuf> {
+    pulseaudio::socket_path_from_env().context("PulseAudio not available")
+}
+
+/// Authentication with the cookie of the user, offering shared memory if `shm`.
+fn auth_params(shm: bool) -> protocol::AuthParams {
+    let cookie = pulseaudio::cookie_path_from_env()
+        .and_then(|path| std::fs::read(path).ok())
+        .unwrap_or_default();
+    protocol::AuthParams {
+        version: protocol::MAX_VERSION,
+        supports_shm: shm,
+        supports_memfd: shm,
+        cookie,
+    }
+}
+
+fn client_props() -> protocol::Props {
+    let mut props = protocol::Props::new();
+    props.set(
+        protocol::Prop::ApplicationName,
+        CString::new("pulseaudio-rs-playback").unwrap(),
+    );
+    props
+}
+
+fn connect_and_init() -> anyhow::Result<(BufReader<UnixStream>, u16)> {
+    let mut sock = std::io::BufReader::new(UnixStream::connect(socket_path()?)?);
+
+    protocol::write_command_message(
+        sock.get_mut(),
+        0,
+        protocol::Command::Auth(auth_params(false)),
+        protocol::MAX_VERSION,
+    )?;
+
+    let (_, auth_reply) =
+        protocol::read_reply_message::<protocol::AuthReply>(&mut sock, protocol::MAX_VERSION)?;
+    let protocol_version = std::cmp::min(protocol::MAX_VERSION, auth_reply.version);
+
+    protocol::write_command_message(
+        sock.get_mut(),
+        1,
+        protocol::Command::SetClientName(client_props()),
+        protocol_version,
+    )?;
+
+    let _ =
+        protocol::read_reply_message::<protocol::SetClientNameReply>(&mut sock, protocol_version)?;
+    Ok((sock, protocol_version))
+}
@@ -0,0 +1,137 @@
+// SPDX-License-Identifier: EUPL-1.2
+
+use std::{
+    collections::{hash_map::Entry, HashMap, VecDeque},
+    fs::File,
+    io, mem,
+    os::fd::{AsRawFd, FromRawFd, OwnedFd, RawFd},
+    ptr, slice,
+};
+
+// Flags of the descriptor of a message, as in `pulsecore/pstream.c`.
+pub const FLAG_SHMDATA: u32 = 0x8000_0000;
+pub const FLAG_SHMDATA_MEMFD_BLOCK: u32 = 0x2000_0000;
+pub const FLAG_SHMRELEASE: u32 = 0x4000_0000;
+pub const FLAG_SHMREVOKE: u32 = 0xC000_0000;
+pub const FLAG_SHMMASK: u32 = 0xFF00_0000;
+
+/// Size of the payload of a data message in shared memory: block, segment, offset and length.
+pub const SHM_INFO_SIZE: usize = 16;
+
+const MAX_FDS: usize = 8; // Descriptors received with a single message
+
+/// A shared memory segment of the server, mapped read only. The descriptor is closed once
+/// mapped, the mapping is released on drop.
+struct Segment {
+    ptr: *mut libc::c_void,
+    len: usize,
+}
+
+// The mapping is only read, and owned by a single `Segment`.
+unsafe impl Send for Segment {}
+
+impl Segment {
+    fn map(file: File) -> io::Result<Segment> {
+        let len = file.metadata()?.len() as usize;
+        if len == 0 {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, "empty segment"));
+        }
+        let ptr = unsafe {
+            libc::mmap(
+                ptr::null_mut(),
+                len,
+                libc::PROT_READ,
+                libc::MAP_SHARED,
+                file.as_raw_fd(),
+                0,
+            )
+        };
+        if ptr == libc::MAP_FAILED {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(Segment { ptr, len })
+    }
+
+    fn get(&self, offset: usize, len: usize) -> Option<&[u8]> {
+        let end = offset.checked_add(len).filter(|&end| end <= self.len)?;
+        let bytes = unsafe { slice::from_raw_parts(self.ptr as *const u8, self.len) };
+        Some(&bytes[offset..end])
+    }
+}
+
+impl Drop for Segment {
+    fn drop(&mut self) {
+        unsafe { libc::munmap(self.ptr, self.len) };
+    }
+}
+
+/// The segments of the server that blocks of samples are read from: memfd segments registered by
+/// the server, and POSIX segments attached on first use.
+#[derive(Default)]
+pub struct Segments {
+    memfd: HashMap<u32, Segment>,
+    posix: HashMap<u32, Segment>,
+}
+
+impl Segments {
+    pub fn register_memfd(&mut self, shm_id: u32, fd: OwnedFd) -> io::Result<()> {
+        self.memfd.insert(shm_id, Segment::map(File::from(fd))?);
+        Ok(())
+    }
+
+    /// The bytes of a block at `offset` in a segment.
+    pub fn block(&mut self, memfd: bool, shm_id: u32, offset: u32, len: u32) -> io::Result<&[u8]> {
+        let segment = if memfd {
+            self.memfd.get(&shm_id).ok_or_else(|| {
+                io::Error::new(io::ErrorKind::NotFound, "unregistered memfd segment")
+            })?
+        } else {
+            match self.posix.entry(shm_id) {
+                Entry::Occupied(entry) => entry.into_mut(),
+                Entry::Vacant(entry) => {
+                    let file = File::open(format!("/dev/shm/pulse-shm-{}", shm_id))?;
+                    entry.insert(Segment::map(file)?)
+                }
+            }
+        };
+        segment
+            .get(offset as usize, len as usize)
+            .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, "block out of its segment"))
+    }
+}
+
+/// Reads from a socket like `read`, queueing the descriptors passed along with the bytes.
+pub fn recv(fd: RawFd, buf: &mut [u8], fds: &mut VecDeque<OwnedFd>) -> io::Result<usize> {
+    // u64 keeps the control buffer aligned for `cmsghdr`.
+    let mut control = [0u64; 8 + MAX_FDS / 2];
+    let mut iov = libc::iovec {
+        iov_base: buf.as_mut_ptr().cast(),
+        iov_len: buf.len(),
+    };
+    let mut msg: libc::msghdr = unsafe { mem::zeroed() };
+    msg.msg_iov = &mut iov;
+    msg.msg_iovlen = 1;
+    msg.msg_control = control.as_mut_ptr().cast();
+    msg.msg_controllen = mem::size_of_val(&control) as _;
+
+    let len = unsafe { libc::recvmsg(fd, &mut msg, libc::MSG_CMSG_CLOEXEC) };
+    if len < 0 {
+        return Err(io::Error::last_os_error());
+    }
+
+    let mut cmsg = unsafe { libc::CMSG_FIRSTHDR(&msg) };
+    while !cmsg.is_null() {
+        let header = unsafe { &*cmsg };
+        if header.cmsg_level == libc::SOL_SOCKET && header.cmsg_type == libc::SCM_RIGHTS {
+            let data = unsafe { libc::CMSG_DATA(cmsg) } as *const RawFd;
+            let count = (header.cmsg_len as usize - unsafe { libc::CMSG_LEN(0) } as usize)
+                / mem::size_of::<RawFd>();
+            for i in 0..count {
+                let fd = unsafe { ptr::read_unaligned(data.add(i)) };
+                fds.push_back(unsafe { OwnedFd::from_raw_fd(fd) });
+            }
+        }
+        cmsg = unsafe { libc::CMSG_NXTHDR(&msg, cmsg) };
+    }
+    Ok(len as usize)
+}
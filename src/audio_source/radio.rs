@@ -0,0 +1,446 @@
+// SPDX-License-Identifier: EUPL-1.2
+
+use std::{
+    io::{self, BufRead, BufReader, Read, Write},
+    net::TcpStream,
+    process::{Child, ChildStdin, Command, Stdio},
+    sync::{mpsc, Arc},
+    thread,
+    time::Instant,
+};
+
+use anyhow::{bail, Context};
+use pulseaudio::protocol::SampleFormat;
+
+use super::{
+    pcm::{PcmSource, PcmSpec, PipeReader},
+    pulse::Playback,
+    AudioSource, Notice,
+};
+
+const SAMPLE_RATE: u32 = 44100;
+const CHANNELS: u8 = 2;
+const MAX_REDIRECTS: usize = 5;
+const USER_AGENT: &str = concat!("isis/", env!("CARGO_PKG_VERSION"));
+
+/// Streams an Icecast or Shoutcast station. The stream is decoded by `ffmpeg`, which handles MP3,
+/// AAC, Ogg Vorbis and Opus alike, and played on the default sink as it is read.
+pub struct RadioSource {
+    decoder: Child,
+    pcm: PcmSource<PipeReader>,
+    playback: Playback,
+    titles: mpsc::Receiver<String>,
+}
+
+impl RadioSource {
+    pub fn open(url: &str) -> anyhow::Result<RadioSource> {
+        let stream = IcyStream::open(url)?;
+        eprintln!("streaming from: {}...", stream.name().unwrap_or(url));
+
+        let mut decoder = Command::new("ffmpeg")
+            .args(["-hide_banner", "-loglevel", "error", "-i", "pipe:0"])
+            .args(["-f", "f32le", "-ac", &CHANNELS.to_string()])
+            .args(["-ar", &SAMPLE_RATE.to_string(), "pipe:1"])
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .spawn()
+            .context("failed to start ffmpeg")?;
+        let stdin = decoder.stdin.take().unwrap();
+        let stdout = decoder.stdout.take().unwrap();
+
+        let (title_tx, titles) = mpsc::channel();
+        thread::spawn(move || {
+            if let Err(err) = feed(stream, stdin, title_tx) {
+                eprintln!("radio stream interrupted: {}", err);
+            }
+        });
+
+        let spec = PcmSpec {
+            format: SampleFormat::Float32Le,
+            sample_rate: SAMPLE_RATE,
+            channels: CHANNELS,
+        };
+        Ok(RadioSource {
+            decoder,
+            pcm: PcmSource::new(PipeReader::new(stdout)?, spec)?,
+            playback: Playback::open(SAMPLE_RATE, CHANNELS)?,
+            titles,
+        })
+    }
+}
+
+impl AudioSource for RadioSource {
+    fn sample_rate(&self) -> u32 {
+        SAMPLE_RATE
+    }
+
+    fn channels(&self) -> usize {
+        CHANNELS as usize
+    }
+
+    // The station sends its audio in real time, and the samples are played as they are read: they
+    // can not be analyzed any faster.
+    fn is_live(&self) -> bool {
+        true
+    }
+
+    fn read(&mut self, out: &mut Vec<f32>) -> anyhow::Result<bool> {
+        let len = out.len();
+        let more = self.pcm.read(out)?;
+        if out.len() > len {
+            self.playback.write(&out[len..])?;
+        }
+        Ok(more)
+    }
+
+    fn notice(&mut self) -> Option<Notice> {
+        self.titles.try_recv().ok().map(Notice::Track)
+    }
+
+    fn time(&self) -> Option<Instant> {
+        Some(self.playback.time())
+    }
+}
+
+impl Drop for RadioSource {
+    fn drop(&mut self) {
+        let _ = self.decoder.kill();
+        let _ = self.decoder.wait();
+    }
+}
+
+/// Copies the audio of `stream` to the decoder, and its titles to `title_tx`.
+fn feed(
+    mut stream: IcyStream,
+    mut decoder: ChildStdin,
+    title_tx: mpsc::Sender<String>,
+) -> io::Result<()> {
+    let mut buf = vec![0; 4096];
+    loop {
+        let len = stream.read(&mut buf)?;
+        if let Some(title) = stream.take_title() {
+            eprintln!("now playing: {}", title);
+            let _ = title_tx.send(title);
+        }
+        if len == 0 {
+            return Ok(());
+        }
+        decoder.write_all(&buf[..len])?;
+    }
+}
+
+trait Connection: Read + Write + Send {}
+
+impl<T: Read + Write + Send> Connection for T {}
+
+/// The audio of an HTTP(S) stream, with the ICY metadata taken out.
+pub struct IcyStream {
+    reader: BufReader<Box<dyn Connection>>,
+    name: Option<String>,
+    metaint: Option<usize>,
+    until_metadata: usize,
+    title: Option<String>,
+    title_changed: bool,
+}
+
+enum Response {
+    Stream(IcyStream),
+    Redirect(String),
+}
+
+impl IcyStream {
+    /// Requests the stream at `url`, following redirects.
+    pub fn open(url: &str) -> anyhow::Result<IcyStream> {
+        let mut url = url.to_owned();
+        for _ in 0..=MAX_REDIRECTS {
+            match request(&url)? {
+                Response::Stream(stream) => return Ok(stream),
+                Response::Redirect(location) => url = location,
+            }
+        }
+        bail!("too many redirects")
+    }
+
+    /// Name of the station, from the `icy-name` header.
+    pub fn name(&self) -> Option<&str> {
+        self.name.as_deref()
+    }
+
+    /// Returns the title of the current track if it changed since the last call.
+    pub fn take_title(&mut self) -> Option<String> {
+        if !self.title_changed {
+            return None;
+        }
+        self.title_changed = false;
+        self.title.clone()
+    }
+
+    fn read_metadata(&mut self) -> io::Result<()> {
+        let mut len = [0];
+        if self.reader.read(&mut len)? == 0 {
+            return Ok(());
+        }
+        let mut metadata = vec![0; len[0] as usize * 16];
+        self.reader.read_exact(&mut metadata)?;
+        if let Some(title) = stream_title(&metadata) {
+            if self.title.as_ref() != Some(&title) {
+                self.title = Some(title);
+                self.title_changed = true;
+            }
+        }
+        Ok(())
+    }
+}
+
+impl Read for IcyStream {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let Some(metaint) = self.metaint else {
+            return self.reader.read(buf);
+        };
+        if self.until_metadata == 0 {
+            self.read_metadata()?;
+            self.until_metadata = metaint;
+        }
+        let len = buf.len().min(self.until_metadata);
+        let len = self.reader.read(&mut buf[..len])?;
+        self.until_metadata -= len;
+        Ok(len)
+    }
+}
+
+/// Parses `StreamTitle='Artist - Title';` out of an ICY metadata block.
+fn stream_title(metadata: &[u8]) -> Option<String> {
+    const KEY: &str = "StreamTitle='";
+    let metadata = String::from_utf8_lossy(metadata);
+    let metadata = metadata.trim_end_matches('\0');
+    let start = metadata.find(KEY)? + KEY.len();
+    let end = metadata[start..]
+        .find("';")
+        .map_or(metadata.len(), |end| start + end);
+    let title = metadata[start..end].trim_end_matches('\'').trim();
+    (!title.is_empty()).then(|| title.to_owned())
+}
+
+struct Url<'a> {
+    tls: bool,
+    host: &'a str,
+    port: u16,
+    path: &'a str,
+}
+
+fn parse_url(url: &str) -> anyhow::Result<Url<'_>> {
+    let (tls, rest) = if let Some(rest) = url.strip_prefix("http://") {
+        (false, rest)
+    } else if let Some(rest) = url.strip_prefix("https://") {
+        (true, rest)
+    } else {
+        bail!("unsupported URL {:?}, expected http:// or https://", url);
+    };
+    let rest = rest.split('#').next().unwrap_or_default();
+    let (authority, path) = match rest.find('/') {
+        Some(i) => (&rest[..i], &rest[i..]),
+        None => (rest, "/"),
+    };
+    let (host, port) = match authority.rsplit_once(':') {
+        Some((host, port)) => (host, port.parse().context("invalid port")?),
+        None => (authority, if tls { 443 } else { 80 }),
+    };
+    if host.is_empty() {
+        bail!("no host in URL {:?}", url);
+    }
+    Ok(Url {
+        tls,
+        host,
+        port,
+        path,
+    })
+}
+
+fn request(url: &str) -> anyhow::Result<Response> {
+    let Url {
+        tls,
+        host,
+        port,
+        path,
+    } = parse_url(url)?;
+
+    let tcp = TcpStream::connect((host, port))
+        .with_context(|| format!("failed to connect to {}:{}", host, port))?;
+    let mut connection: Box<dyn Connection> = if tls {
+        Box::new(tls_connect(host, tcp)?)
+    } else {
+        Box::new(tcp)
+    };
+
+    // HTTP/1.0 keeps the body free of chunked encoding.
+    write!(
+        connection,
+        "GET {} HTTP/1.0\r\nHost: {}:{}\r\nUser-Agent: {}\r\nAccept: */*\r\nIcy-MetaData: 1\r\n\r\n",
+        path, host, port, USER_AGENT
+    )?;
+    connection.flush()?;
+
+    let mut reader = BufReader::new(connection);
+    let mut status = String::new();
+    reader.read_line(&mut status)?;
+    // Old Shoutcast servers answer with an `ICY 200 OK` status line.
+    let mut parts = status.split_whitespace();
+    let code = match (parts.next(), parts.next()) {
+        (Some(protocol), Some(code)) if protocol.starts_with("HTTP/") || protocol == "ICY" => {
+            code.parse::<u16>().ok()
+        }
+        _ => None,
+    };
+    let Some(code) = code else {
+        bail!("invalid response from {}: {:?}", url, status.trim());
+    };
+
+    let (mut name, mut metaint, mut location) = (None, None, None);
+    loop {
+        let mut line = String::new();
+        if reader.read_line(&mut line)? == 0 || line.trim().is_empty() {
+            break;
+        }
+        let Some((header, value)) = line.split_once(':') else {
+            continue;
+        };
+        let value = value.trim().to_owned();
+        match header.trim().to_ascii_lowercase().as_str() {
+            "icy-name" => name = Some(value),
+            "icy-metaint" => metaint = Some(value.parse().context("invalid icy-metaint")?),
+            "location" => location = Some(value),
+            _ => {}
+        }
+    }
+
+    match code {
+        200 => Ok(Response::Stream(IcyStream {
+            reader,
+            name,
+            metaint: metaint.filter(|&metaint| metaint > 0),
+            until_metadata: metaint.unwrap_or_default(),
+            title: None,
+            title_changed: false,
+        })),
+        301 | 302 | 303 | 307 | 308 => {
+            let location = location.context("redirect without location")?;
+            Ok(Response::Redirect(if location.starts_with('/') {
+                let scheme = if tls { "https" } else { "http" };
+                format!("{}://{}:{}{}", scheme, host, port, location)
+            } else {
+                location
+            }))
+        }
+        _ => bail!("{} replied {}", url, status.trim()),
+    }
+}
+
+fn tls_connect(
+    host: &str,
+    tcp: TcpStream,
+) -> anyhow::Result<rustls::StreamOwned<rustls::ClientConnection, TcpStream>> {
+    let roots = rustls::RootCertStore::from_iter(webpki_roots::TLS_SERVER_ROOTS.iter().cloned());
+    let config = rustls::ClientConfig::builder_with_provider(Arc::new(
+        rustls::crypto::ring::default_provider(),
+    ))
+    .with_safe_default_protocol_versions()?
+    .with_root_certificates(roots)
+    .with_no_client_auth();
+    let name = rustls::pki_types::ServerName::try_from(host.to_owned())?;
+    let connection = rustls::ClientConnection::new(Arc::new(config), name)?;
+    Ok(rustls::StreamOwned::new(connection, tcp))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::TcpListener;
+
+    /// Serves one of `responses` per connection on a local port, and returns the URL to it.
+    fn serve(responses: Vec<Vec<u8>>) -> String {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("http://{}/stream", listener.local_addr().unwrap());
+        thread::spawn(move || {
+            for response in responses {
+                let (stream, _) = listener.accept().unwrap();
+                let mut reader = BufReader::new(stream);
+                let mut request = String::new();
+                while !request.ends_with("\r\n\r\n") {
+                    reader.read_line(&mut request).unwrap();
+                }
+                assert!(request.starts_with("GET /stream HTTP/1.0\r\n"));
+                assert!(request.contains("\r\nIcy-MetaData: 1\r\n"));
+                reader.get_mut().write_all(&response).unwrap();
+            }
+        });
+        url
+    }
+
+    fn metadata(text: &str) -> Vec<u8> {
+        let mut block = text.as_bytes().to_vec();
+        block.resize(text.len().div_ceil(16) * 16, 0);
+        let mut metadata = vec![(block.len() / 16) as u8];
+        metadata.extend(block);
+        metadata
+    }
+
+    #[test]
+    fn icy_metadata() {
+        let audio: Vec<u8> = (0..40).collect();
+        let blocks = [
+            "StreamTitle='Burning Spear - Marcus Garvey';StreamUrl='';",
+            "",
+            "StreamTitle='Burning Spear - Marcus Garvey';",
+            "StreamTitle='Culture - Two Sevens Clash';",
+        ];
+        let mut response = b"ICY 200 OK\r\nicy-name: Roots FM\r\nicy-metaint: 8\r\n\r\n".to_vec();
+        for (chunk, block) in audio.chunks(8).zip(blocks.iter().chain([&""])) {
+            response.extend(chunk);
+            response.extend(metadata(block));
+        }
+
+        let mut stream = IcyStream::open(&serve(vec![response])).unwrap();
+        assert_eq!(stream.name(), Some("Roots FM"));
+        let (mut read, mut titles): (Vec<u8>, Vec<String>) = (Vec::new(), Vec::new());
+        let mut buf = [0; 5];
+        loop {
+            let len = stream.read(&mut buf).unwrap();
+            titles.extend(stream.take_title());
+            if len == 0 {
+                break;
+            }
+            read.extend(&buf[..len]);
+        }
+        assert_eq!(read, audio);
+        assert_eq!(
+            titles,
+            [
+                "Burning Spear - Marcus Garvey",
+                "Culture - Two Sevens Clash"
+            ]
+        );
+    }
+
+    #[test]
+    fn redirect() {
+        let audio: Vec<u8> = (0..100).collect();
+        let mut response = b"HTTP/1.0 200 OK\r\nContent-Type: audio/mpeg\r\n\r\n".to_vec();
+        response.extend(&audio);
+        let url = serve(vec![
+            b"HTTP/1.1 302 Found\r\nLocation: /stream\r\n\r\n".to_vec(),
+            response,
+        ]);
+
+        let mut stream = IcyStream::open(&url).unwrap();
+        let mut read = Vec::new();
+        stream.read_to_end(&mut read).unwrap();
+        assert_eq!(read, audio);
+        assert_eq!(stream.take_title(), None);
+    }
+
+    #[test]
+    fn error_status() {
+        let url = serve(vec![b"HTTP/1.1 404 Not Found\r\n\r\n".to_vec()]);
+        assert!(IcyStream::open(&url).is_err());
+    }
+}
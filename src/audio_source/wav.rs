@@ -0,0 +1,53 @@
+// SPDX-License-Identifier: EUPL-1.2
+
+use std::{fs::File, io::BufReader, path::Path};
+
+use anyhow::Context;
+use hound::{SampleFormat, WavReader};
+
+use super::{AudioSource, CHUNK};
+
+/// Reads a WAV file.
+pub struct WavSource {
+    reader: WavReader<BufReader<File>>,
+    scale: f32,
+}
+
+impl WavSource {
+    pub fn open(path: &Path) -> anyhow::Result<WavSource> {
+        let reader =
+            WavReader::open(path).with_context(|| format!("failed to open {}", path.display()))?;
+        eprintln!("reading from file: {:?}...", path);
+        eprintln!("spec: {:#?}", reader.spec());
+        let scale = 1.0 / (1u64 << (reader.spec().bits_per_sample - 1)) as f32;
+        Ok(WavSource { reader, scale })
+    }
+}
+
+impl AudioSource for WavSource {
+    fn sample_rate(&self) -> u32 {
+        self.reader.spec().sample_rate
+    }
+
+    fn channels(&self) -> usize {
+        self.reader.spec().channels as usize
+    }
+
+    fn read(&mut self, out: &mut Vec<f32>) -> anyhow::Result<bool> {
+        let len = out.len();
+        let count = CHUNK * self.channels();
+        match self.reader.spec().sample_format {
+            SampleFormat::Float => {
+                for sample in self.reader.samples::<f32>().take(count) {
+                    out.push(sample.context("failed to read samples")?);
+                }
+            }
+            SampleFormat::Int => {
+                for sample in self.reader.samples::<i32>().take(count) {
+                    out.push(sample.context("failed to read samples")? as f32 * self.scale);
+                }
+            }
+        }
+        Ok(out.len() > len)
+    }
+}
@@ -1,5 +1,8 @@
 // SPDX-License-Identifier: EUPL-1.2
 
+use std::collections::VecDeque;
+use std::time::Instant;
+
 use anyhow::Result;
 use dbus::blocking::Connection;
 use lockfree::channel::spsc;
@@ -8,17 +11,27 @@ use macroquad::prelude::*;
 use miniquad;
 
 use crate::audio_analyzer;
+use crate::audio_analyzer::key;
+use crate::audio_source::shift;
 use crate::screensaver;
 
 const R: f32 = 0.000976;
 const D_MIN: f32 = 0.146;
 const D_MAX: f32 = 1.0;
+const P_MAX: f32 = 0.382;
+const F_T: f32 = 0.146; // Beat flash decay time in seconds
+const PHRASE: u64 = 4; // Bars between two colour swaps
 const S_R: f32 = 0.05;
 const S_V: f32 = 0.382;
+const S_H: f32 = 0.382;
+
+const HUE_C: f32 = 0.049; // Hue of the `a` colour in C major and A minor
+const KEY_MIN: f32 = 0.5; // Key confidence needed to change the palette
 
 const BPM_MIN: f32 = 200.0;
 
-pub fn run(mut event_rx: spsc::Receiver<audio_analyzer::Event>) -> () {
+/// Shows the events of `event_rx`, `offset` milliseconds after the time they are heard.
+pub fn run(mut event_rx: spsc::Receiver<audio_analyzer::Timed>, offset: i64) -> () {
     macroquad::Window::from_config(
         Conf {
             window_title: "isis".to_owned(),
@@ -26,7 +39,7 @@ pub fn run(mut event_rx: spsc::Receiver<audio_analyzer::Event>) -> () {
             ..Default::default()
         },
         async move {
-            if let Err(err) = arun(&mut event_rx).await {
+            if let Err(err) = arun(&mut event_rx, offset).await {
                 {
                     let lvl = miniquad::log::Level::Error;
                     miniquad::log::__private_api_log_lit(
@@ -40,7 +53,7 @@ pub fn run(mut event_rx: spsc::Receiver<audio_analyzer::Event>) -> () {
     );
 }
 
-pub async fn arun(event_rx: &mut spsc::Receiver<audio_analyzer::Event>) -> Result<()> {
+pub async fn arun(event_rx: &mut spsc::Receiver<audio_analyzer::Timed>, offset: i64) -> Result<()> {
     let texture: Texture2D = load_texture("/mnt/data/homes/nixos/research/isis/chess.png")
         .await
         .unwrap();
@@ -54,6 +67,8 @@ pub async fn arun(event_rx: &mut spsc::Receiver<audio_analyzer::Event>) -> Resul
             uniforms: vec![
                 UniformDesc::new("Center", UniformType::Float2),
                 UniformDesc::new("sign_o", UniformType::Float1),
+                UniformDesc::new("flash", UniformType::Float1),
+                UniformDesc::new("hue", UniformType::Float1),
             ],
             ..Default::default()
         },
@@ -62,21 +77,26 @@ pub async fn arun(event_rx: &mut spsc::Receiver<audio_analyzer::Event>) -> Resul
 
     let mut audio_bpm: f32 = BPM_MIN;
     let mut audio_rms: f32 = 0.0;
+    let mut audio_balance: f32 = 0.0;
+    let mut audio_hue: f32 = HUE_C;
 
     let mut bpm: f32 = BPM_MIN * 0.618;
     let mut rms: f32 = 0.0;
+    let mut balance: f32 = 0.0;
+    let mut flash: f32 = 0.0;
+    let mut hue: f32 = HUE_C;
 
     let minimum_frame_time = 1. / 30.; // 24 FPS
     let mut frame_time = 0.0;
     let mut theta: f32 = 0.0;
     let mut sign_a: f32 = 1.0;
-    // TODO Find interesting usage for this one
-    //let mut sign_o: f32 = -sign_a;
+    let mut sign_o: f32 = -sign_a;
 
     show_mouse(false);
 
     let conn = Connection::new_session()?;
     let mut cookie: Option<u32> = None;
+    let mut pending: VecDeque<audio_analyzer::Timed> = VecDeque::new();
 
     loop {
         // check for input or screen saver to exit
@@ -88,19 +108,51 @@ pub async fn arun(event_rx: &mut spsc::Receiver<audio_analyzer::Event>) -> Resul
             println!("exit because screen saver is on");
             break;
         }
-        // receive events
+        // receive events, and hold each one back until it is heard, in the order they are heard
         loop {
             match event_rx.recv() {
-                Ok(audio_analyzer::Event::Reset) => {
+                Ok(timed) => {
+                    let at = pending.partition_point(|p| p.time <= timed.time);
+                    pending.insert(at, timed);
+                }
+                Err(RecvErr::NoMessage) => break,
+                Err(err) => {
+                    eprintln!("{:?}", err);
+                    break;
+                }
+            }
+        }
+        let now = Instant::now();
+        while pending
+            .front()
+            .is_some_and(|timed| shift(timed.time, offset as f64 / 1000.0) <= now)
+        {
+            let timed = pending.pop_front().unwrap();
+            match timed.event {
+                audio_analyzer::Event::Reset => {
                     audio_bpm = BPM_MIN;
                     audio_rms = 0.0;
+                    audio_balance = 0.0;
+                    audio_hue = HUE_C;
 
                     sign_a = -sign_a;
                 }
-                Ok(audio_analyzer::Event::Tempo {
+                audio_analyzer::Event::SourceLost => {
+                    // Drop the stale state, and let the screen saver in until music comes back.
+                    audio_bpm = BPM_MIN;
+                    audio_rms = 0.0;
+                    audio_balance = 0.0;
+                    flash = 0.0;
+
+                    if let Some(c) = cookie.take() {
+                        screensaver::uninhibit(&conn, c).unwrap();
+                    }
+                }
+                audio_analyzer::Event::SourceRestored => {}
+                audio_analyzer::Event::Tempo {
                     average: bpm,
                     accuracy: _,
-                }) => {
+                } => {
                     audio_bpm = bpm;
                     if cookie.is_none() {
                         cookie = Some(
@@ -113,14 +165,55 @@ pub async fn arun(event_rx: &mut spsc::Receiver<audio_analyzer::Event>) -> Resul
                         );
                     }
                 }
-                Ok(audio_analyzer::Event::Volume { average: rms }) => {
+                audio_analyzer::Event::Volume { average: rms } => {
                     audio_rms = rms;
                 }
-                Err(RecvErr::NoMessage) => {
-                    break;
+                audio_analyzer::Event::Stereo { balance, width: _ } => {
+                    audio_balance = balance;
                 }
-                Err(err) => {
-                    eprintln!("{:?}", err);
+                audio_analyzer::Event::ChannelVolume { .. } => {}
+                audio_analyzer::Event::Loudness { .. } => {}
+                audio_analyzer::Event::Spectrum { .. } => {}
+                audio_analyzer::Event::Groove { .. } => {}
+                audio_analyzer::Event::Section { .. } => {
+                    // A new section is a new scene: turn the other way.
+                    sign_a = -sign_a;
+                }
+                audio_analyzer::Event::Timbre { .. } => {}
+                audio_analyzer::Event::Chroma { .. } => {}
+                audio_analyzer::Event::Key {
+                    tonic,
+                    mode,
+                    confidence,
+                } => {
+                    // Around the circle of fifths, so that close keys get close colours.
+                    if confidence > KEY_MIN {
+                        let key = key::Key {
+                            tonic,
+                            mode,
+                            confidence,
+                        };
+                        audio_hue = HUE_C + key.fifths() as f32 / 12.0;
+                    }
+                }
+                audio_analyzer::Event::Track { .. } => {}
+                audio_analyzer::Event::Breakdown { .. } => {}
+                audio_analyzer::Event::Dominant { .. } => {}
+                audio_analyzer::Event::Diagnostic(_) => {}
+                audio_analyzer::Event::Beat {
+                    time: _,
+                    strength,
+                    phase: _,
+                } => {
+                    flash = flash.max(strength);
+                }
+                audio_analyzer::Event::Bar {
+                    index,
+                    beats_per_bar: _,
+                } => {
+                    if index % PHRASE == 0 {
+                        sign_o = -sign_o;
+                    }
                 }
             }
         }
@@ -136,6 +229,18 @@ pub async fn arun(event_rx: &mut spsc::Receiver<audio_analyzer::Event>) -> Resul
             rms += rms_delta * frame_time * S_V;
         }
 
+        let balance_delta = audio_balance - balance;
+        if balance_delta != 0.0 {
+            balance += balance_delta * frame_time * S_V;
+        }
+
+        let hue_delta = (audio_hue - hue + 0.5).rem_euclid(1.0) - 0.5;
+        if hue_delta != 0.0 {
+            hue = (hue + hue_delta * frame_time * S_H).rem_euclid(1.0);
+        }
+
+        flash *= (-frame_time / F_T).exp();
+
         // animate
         let screen_size = vec2(screen_width(), screen_height());
         let screen_center = screen_size / 2.0;
@@ -160,6 +265,7 @@ pub async fn arun(event_rx: &mut spsc::Receiver<audio_analyzer::Event>) -> Resul
                 + theta.cos() * (lens_center.y - screen_center.y)
                 + screen_center.y,
         );
+        lens_center.x += screen_center_min * P_MAX * balance;
 
         // draw
         clear_background(WHITE);
@@ -175,7 +281,9 @@ pub async fn arun(event_rx: &mut spsc::Receiver<audio_analyzer::Event>) -> Resul
         );
 
         lens_material.set_uniform("Center", lens_center);
-        //lens_material.set_uniform("sign_o", sign_o);
+        lens_material.set_uniform("flash", flash);
+        lens_material.set_uniform("sign_o", sign_o);
+        lens_material.set_uniform("hue", hue);
 
         gl_use_material(&lens_material);
         draw_circle(lens_center.x, lens_center.y, screen_center_min * 5.0, RED);
@@ -208,9 +316,16 @@ varying vec2 uv;
 varying vec2 uv_screen;
 varying vec2 center;
 uniform float sign_o;
+uniform float flash;
+uniform float hue;
 
 uniform sampler2D _ScreenTexture;
 
+vec3 hsv(float h, float s, float v) {
+    vec3 k = clamp(abs(mod(h * 6.0 + vec3(0.0, 4.0, 2.0), 6.0) - 3.0) - 1.0, 0.0, 1.0);
+    return v * mix(vec3(1.0), k, s);
+}
+
 void main() {
     float gradient = length(uv);
     vec2 uv_zoom = (uv_screen - center) * gradient + center;
@@ -218,17 +333,17 @@ void main() {
     gl_FragColor = texture2D(_ScreenTexture, uv_zoom);
 
     float lum = 0.618;
-    vec4 a = vec4(1.0, 0.4278, 0.1894, 1.0);
-    vec4 o = vec4(0.5497, 0.4136, 1.0, 1.0);
+    vec4 a = vec4(hsv(hue, 0.8106, 1.0), 1.0);
+    vec4 o = vec4(hsv(hue + 0.6563, 0.5864, 1.0), 1.0);
 
-    if (gl_FragColor == vec4(1.0)) {
+    if ((gl_FragColor == vec4(1.0)) == (sign_o < 0.0)) {
         gl_FragColor = a;
     } else {
         //gl_FragColor = vec4(0.831, 0.235, 1.0, 1.0);
         gl_FragColor = o;
     }
 
-    gl_FragColor = gl_FragColor * lum;
+    gl_FragColor = gl_FragColor * mix(lum, 1.0, flash);
 }
 "#;
 
@@ -2,6 +2,8 @@
 
 pub mod angel;
 pub mod audio_analyzer;
+pub mod audio_source;
 pub mod display;
 pub mod openrgb;
+pub mod sample;
 pub mod screensaver;
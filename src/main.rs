@@ -1,22 +1,160 @@
 // SPDX-License-Identifier: EUPL-1.2
 
+use anyhow::{bail, Context, Result};
 use lockfree::channel::spsc;
+use std::num::{NonZeroU32, NonZeroU8};
 use std::thread;
 
-use isis::{angel, audio_analyzer, display};
+use isis::{
+    angel, audio_analyzer,
+    audio_source::{self, pulse, Input},
+    display, sample,
+};
 
 pub fn main() -> () {
-    match std::env::args().nth(1) {
+    let mut args = std::env::args().skip(1);
+    match args.next() {
         Some(arg) if arg == "--angel" => {
             angel::run().unwrap();
         }
+        Some(arg) if arg == "sources" => {
+            let (server_info, source_infos) = match pulse::sources() {
+                Ok(sources) => sources,
+                Err(err) => {
+                    eprintln!("isis: {:#}", err);
+                    std::process::exit(1);
+                }
+            };
+            for info in source_infos {
+                let default = Some(&info.name) == server_info.default_source_name.as_ref();
+                println!(
+                    "{} {}\t{}",
+                    if default { "*" } else { " " },
+                    info.name.to_string_lossy(),
+                    info.description
+                        .as_deref()
+                        .map(|d| d.to_string_lossy())
+                        .unwrap_or_default(),
+                );
+            }
+        }
+        Some(arg) if arg.starts_with("--") => {
+            match parse_config(std::iter::once(arg).chain(args)) {
+                Ok(config) => run(config),
+                Err(err) => eprintln!("isis: {:#}", err),
+            }
+        }
         None => {
-            let (mut event_tx, event_rx) = spsc::create();
-            thread::spawn(move || audio_analyzer::run(&mut event_tx).unwrap());
-            display::run(event_rx);
+            run(audio_analyzer::Config::default());
         }
         Some(arg) => {
             eprintln!("isis: {} is not an isis command.", arg);
         }
     }
 }
+
+fn parse_config(mut args: impl Iterator<Item = String>) -> Result<audio_analyzer::Config> {
+    let mut config = audio_analyzer::Config::default();
+    while let Some(arg) = args.next() {
+        let mut value = || {
+            args.next()
+                .with_context(|| format!("{} needs a value", arg))
+        };
+        match arg.as_str() {
+            "--source" => config.sources.push(pulse::Capture {
+                source: value()?.parse().context("invalid source")?,
+                ..Default::default()
+            }),
+            "--app" => config.sources.push(pulse::Capture {
+                source: pulse::Source::Application(value()?.to_lowercase()),
+                ..Default::default()
+            }),
+            "--gain" => {
+                let gain = value()?.parse().context("invalid gain")?;
+                config
+                    .sources
+                    .last_mut()
+                    .context("--gain applies to the --source or --app before it")?
+                    .gain = gain;
+            }
+            "--mix" => config.mix = value()?.parse().context("invalid mix")?,
+            "--downmix" => config.downmix = value()?.parse().context("invalid downmix")?,
+            "--input" => {
+                config.input = match value()?.as_str() {
+                    "-" => Input::Stdin,
+                    url if url.starts_with("http://") || url.starts_with("https://") => {
+                        Input::Radio(url.to_owned())
+                    }
+                    path => Input::Wav(path.into()),
+                }
+            }
+            #[cfg(feature = "pipewire")]
+            "--pipewire" => config.input = Input::PipeWire,
+            "--generate" => {
+                config.input = Input::Generator(value()?.parse().context("invalid signal")?)
+            }
+            "--format" => config.pcm.format = sample::parse_format(&value()?)?,
+            // Zero would divide by zero further on.
+            "--rate" => {
+                config.pcm.sample_rate = value()?
+                    .parse::<NonZeroU32>()
+                    .context("invalid rate")?
+                    .get()
+            }
+            "--channels" => {
+                config.pcm.channels = value()?
+                    .parse::<NonZeroU8>()
+                    .context("invalid channels")?
+                    .get()
+            }
+            "--attack" => config.agc.attack = value()?.parse().context("invalid attack")?,
+            "--release" => config.agc.release = value()?.parse().context("invalid release")?,
+            "--silence-enter" => {
+                config.silence.enter = value()?.parse().context("invalid level")?
+            }
+            "--silence-exit" => config.silence.exit = value()?.parse().context("invalid level")?,
+            "--breakdown" => config.silence.breakdown = value()?.parse().context("invalid time")?,
+            "--silence-reset" => config.silence.reset = value()?.parse().context("invalid time")?,
+            "--calibrate" => config.silence.calibrate = value()?.parse().context("invalid time")?,
+            "--window" => config.timing.window = value()?.parse().context("invalid window")?,
+            "--fragment" => {
+                config.timing.fragment = value()?.parse().context("invalid fragment")?
+            }
+            "--low-latency" => config.timing.low_latency = true,
+            "--offset" => config.timing.offset = value()?.parse().context("invalid offset")?,
+            "--fast" => config.fast = true,
+            _ => bail!("{} is not an isis option.", arg),
+        }
+    }
+    Ok(config)
+}
+
+fn run(config: audio_analyzer::Config) {
+    let stop = audio_analyzer::Stop::default();
+    let source = match audio_source::open(&config, &stop) {
+        Ok(source) => Some(source),
+        // A server or source that is not up yet is waited for like a lost one.
+        Err(err) if config.input.reconnects() => {
+            eprintln!("failed to open source: {:#}", err);
+            None
+        }
+        Err(err) => {
+            eprintln!("isis: {:#}", err);
+            return;
+        }
+    };
+    let offset = config.timing.offset;
+    let (mut event_tx, event_rx) = spsc::create();
+    let analyzer = thread::spawn({
+        let stop = stop.clone();
+        move || {
+            if let Err(err) = audio_analyzer::supervise(&mut event_tx, source, &config, &stop) {
+                eprintln!("isis: {:#}", err);
+            }
+        }
+    });
+    display::run(event_rx, offset);
+    // The display also returns without finishing, as when its window is closed.
+    stop.stop();
+    let _ = analyzer.join();
+}
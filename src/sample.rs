@@ -0,0 +1,266 @@
+// SPDX-License-Identifier: EUPL-1.2
+
+use anyhow::bail;
+use pulseaudio::protocol::SampleFormat;
+
+const I16_SCALE: f32 = 32768.0;
+const I32_SCALE: f32 = 2147483648.0;
+
+/// Decodes raw PulseAudio samples into normalized `f32` samples in `-1.0..1.0`.
+#[derive(Debug, Clone, Copy)]
+pub struct Decoder {
+    format: SampleFormat,
+}
+
+impl Decoder {
+    pub fn new(format: SampleFormat) -> anyhow::Result<Decoder> {
+        if format == SampleFormat::Invalid {
+            bail!("unsupported sample format: {:?}", format);
+        }
+        Ok(Decoder { format })
+    }
+
+    pub fn format(&self) -> SampleFormat {
+        self.format
+    }
+
+    /// Number of bytes used by a single sample of a single channel.
+    pub fn bytes_per_sample(&self) -> usize {
+        self.format.bytes_per_sample()
+    }
+
+    /// Decodes the interleaved samples of `bytes` and appends them to `out`. A trailing partial
+    /// sample is ignored.
+    pub fn decode(&self, bytes: &[u8], out: &mut Vec<f32>) {
+        match self.format {
+            SampleFormat::Invalid => unreachable!(),
+            SampleFormat::U8 => decode_with(bytes, out, |[b]| (b as f32 - 128.0) / 128.0),
+            SampleFormat::Alaw => decode_with(bytes, out, |[b]| alaw(b) as f32 / I16_SCALE),
+            SampleFormat::Ulaw => decode_with(bytes, out, |[b]| ulaw(b) as f32 / I16_SCALE),
+            SampleFormat::S16Le => {
+                decode_with(bytes, out, |b| i16::from_le_bytes(b) as f32 / I16_SCALE)
+            }
+            SampleFormat::S16Be => {
+                decode_with(bytes, out, |b| i16::from_be_bytes(b) as f32 / I16_SCALE)
+            }
+            SampleFormat::Float32Le => decode_with(bytes, out, f32::from_le_bytes),
+            SampleFormat::Float32Be => decode_with(bytes, out, f32::from_be_bytes),
+            SampleFormat::S32Le => {
+                decode_with(bytes, out, |b| i32::from_le_bytes(b) as f32 / I32_SCALE)
+            }
+            SampleFormat::S32Be => {
+                decode_with(bytes, out, |b| i32::from_be_bytes(b) as f32 / I32_SCALE)
+            }
+            // 24 bit samples are shifted into the most significant bytes of an i32.
+            SampleFormat::S24Le => decode_with(bytes, out, |[b0, b1, b2]| {
+                i32::from_le_bytes([0, b0, b1, b2]) as f32 / I32_SCALE
+            }),
+            SampleFormat::S24Be => decode_with(bytes, out, |[b0, b1, b2]| {
+                i32::from_be_bytes([b0, b1, b2, 0]) as f32 / I32_SCALE
+            }),
+            SampleFormat::S24In32Le => decode_with(bytes, out, |b| {
+                (i32::from_le_bytes(b) << 8) as f32 / I32_SCALE
+            }),
+            SampleFormat::S24In32Be => decode_with(bytes, out, |b| {
+                (i32::from_be_bytes(b) << 8) as f32 / I32_SCALE
+            }),
+        }
+    }
+}
+
+/// Parses a sample format by its ffmpeg name (`s16le`, `f32le`...), or its PulseAudio name for the
+/// formats ffmpeg lacks (`s24-32le`, `s24-32be`).
+pub fn parse_format(s: &str) -> anyhow::Result<SampleFormat> {
+    Ok(match s {
+        "u8" => SampleFormat::U8,
+        "alaw" => SampleFormat::Alaw,
+        "mulaw" => SampleFormat::Ulaw,
+        "s16le" => SampleFormat::S16Le,
+        "s16be" => SampleFormat::S16Be,
+        "f32le" => SampleFormat::Float32Le,
+        "f32be" => SampleFormat::Float32Be,
+        "s32le" => SampleFormat::S32Le,
+        "s32be" => SampleFormat::S32Be,
+        "s24le" => SampleFormat::S24Le,
+        "s24be" => SampleFormat::S24Be,
+        "s24-32le" => SampleFormat::S24In32Le,
+        "s24-32be" => SampleFormat::S24In32Be,
+        _ => bail!("unknown sample format {:?}", s),
+    })
+}
+
+fn decode_with<const N: usize>(bytes: &[u8], out: &mut Vec<f32>, f: impl Fn([u8; N]) -> f32) {
+    out.extend(
+        bytes
+            .chunks_exact(N)
+            .map(|chunk| f(chunk.try_into().unwrap())),
+    );
+}
+
+// G.711 expansion, see https://www.itu.int/rec/T-REC-G.711
+fn alaw(a: u8) -> i16 {
+    let a = a ^ 0x55;
+    let exponent = (a >> 4) & 0x07;
+    let mut sample = (((a & 0x0f) as i16) << 4) + 8;
+    if exponent != 0 {
+        sample = (sample + 0x100) << (exponent - 1);
+    }
+    if a & 0x80 != 0 {
+        sample
+    } else {
+        -sample
+    }
+}
+
+fn ulaw(u: u8) -> i16 {
+    let u = !u;
+    let exponent = (u >> 4) & 0x07;
+    let sample = (((((u & 0x0f) as i16) << 3) + 0x84) << exponent) - 0x84;
+    if u & 0x80 != 0 {
+        -sample
+    } else {
+        sample
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn decode(format: SampleFormat, bytes: &[u8]) -> Vec<f32> {
+        let mut out = Vec::new();
+        Decoder::new(format).unwrap().decode(bytes, &mut out);
+        out
+    }
+
+    const S24_MAX: f32 = 8388607.0 / 8388608.0;
+    const S24_LSB: f32 = 1.0 / 8388608.0;
+
+    #[test]
+    fn u8() {
+        assert_eq!(
+            decode(SampleFormat::U8, &[0x00, 0x80, 0xff]),
+            [-1.0, 0.0, 127.0 / 128.0]
+        );
+    }
+
+    #[test]
+    fn alaw() {
+        // 0xd5 and 0x55 are the smallest codes either side of zero, 0xaa and 0x2a the largest.
+        assert_eq!(
+            decode(SampleFormat::Alaw, &[0xd5, 0x55, 0xaa, 0x2a]),
+            [
+                8.0 / 32768.0,
+                -8.0 / 32768.0,
+                32256.0 / 32768.0,
+                -32256.0 / 32768.0
+            ]
+        );
+    }
+
+    #[test]
+    fn ulaw() {
+        // 0xff and 0x7f are both zero, 0x80 and 0x00 the largest codes.
+        assert_eq!(
+            decode(SampleFormat::Ulaw, &[0xff, 0x7f, 0x80, 0x00]),
+            [0.0, 0.0, 32124.0 / 32768.0, -32124.0 / 32768.0]
+        );
+    }
+
+    #[test]
+    fn s16() {
+        let expected = [-1.0, 32767.0 / 32768.0, 0.0];
+        assert_eq!(
+            decode(SampleFormat::S16Le, &[0x00, 0x80, 0xff, 0x7f, 0x00, 0x00]),
+            expected
+        );
+        assert_eq!(
+            decode(SampleFormat::S16Be, &[0x80, 0x00, 0x7f, 0xff, 0x00, 0x00]),
+            expected
+        );
+    }
+
+    #[test]
+    fn s24() {
+        let expected = [-1.0, S24_MAX, 0.0, -S24_LSB];
+        assert_eq!(
+            decode(
+                SampleFormat::S24Le,
+                &[0x00, 0x00, 0x80, 0xff, 0xff, 0x7f, 0x00, 0x00, 0x00, 0xff, 0xff, 0xff]
+            ),
+            expected
+        );
+        assert_eq!(
+            decode(
+                SampleFormat::S24Be,
+                &[0x80, 0x00, 0x00, 0x7f, 0xff, 0xff, 0x00, 0x00, 0x00, 0xff, 0xff, 0xff]
+            ),
+            expected
+        );
+    }
+
+    #[test]
+    fn s24_in_32() {
+        // The padding byte is ignored, whatever it holds.
+        let expected = [-1.0, S24_MAX, 0.0, -S24_LSB];
+        assert_eq!(
+            decode(
+                SampleFormat::S24In32Le,
+                &[
+                    0x00, 0x00, 0x80, 0x00, 0xff, 0xff, 0x7f, 0xab, 0x00, 0x00, 0x00, 0xff, 0xff,
+                    0xff, 0xff, 0x00
+                ]
+            ),
+            expected
+        );
+        assert_eq!(
+            decode(
+                SampleFormat::S24In32Be,
+                &[
+                    0x00, 0x80, 0x00, 0x00, 0xab, 0x7f, 0xff, 0xff, 0xff, 0x00, 0x00, 0x00, 0x00,
+                    0xff, 0xff, 0xff
+                ]
+            ),
+            expected
+        );
+    }
+
+    #[test]
+    fn s32() {
+        let expected = [-1.0, 1.0, 0.0];
+        assert_eq!(
+            decode(
+                SampleFormat::S32Le,
+                &[0x00, 0x00, 0x00, 0x80, 0xff, 0xff, 0xff, 0x7f, 0x00, 0x00, 0x00, 0x00]
+            ),
+            expected
+        );
+        assert_eq!(
+            decode(
+                SampleFormat::S32Be,
+                &[0x80, 0x00, 0x00, 0x00, 0x7f, 0xff, 0xff, 0xff, 0x00, 0x00, 0x00, 0x00]
+            ),
+            expected
+        );
+    }
+
+    #[test]
+    fn float32() {
+        let expected = [-1.0, 1.0, 0.0];
+        let le: Vec<u8> = expected
+            .iter()
+            .flat_map(|s: &f32| s.to_le_bytes())
+            .collect();
+        let be: Vec<u8> = expected
+            .iter()
+            .flat_map(|s: &f32| s.to_be_bytes())
+            .collect();
+        assert_eq!(decode(SampleFormat::Float32Le, &le), expected);
+        assert_eq!(decode(SampleFormat::Float32Be, &be), expected);
+    }
+
+    #[test]
+    fn partial_sample() {
+        assert_eq!(decode(SampleFormat::S16Le, &[0x00, 0x80, 0xff]), [-1.0]);
+    }
+}